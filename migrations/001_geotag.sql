@@ -0,0 +1,6 @@
+-- Capture timestamp from EXIF DateTimeOriginal (camera clock, no timezone)
+-- and a flag for coordinates that were interpolated from a GPX track
+-- instead of being read from the photo itself.
+ALTER TABLE `photos`
+	ADD COLUMN `exif_datetime` DATETIME NULL DEFAULT NULL,
+	ADD COLUMN `gps_derived` TINYINT(1) NOT NULL DEFAULT 0;
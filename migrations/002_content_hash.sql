@@ -0,0 +1,7 @@
+-- SHA-256 of the original file and reference to the canonical copy for
+-- photos that are exact duplicates of another one. Photos with
+-- `duplicate_of` set are hidden from listings.
+ALTER TABLE `photos`
+	ADD COLUMN `content_hash` CHAR(64) NULL DEFAULT NULL,
+	ADD COLUMN `duplicate_of` BIGINT UNSIGNED NULL DEFAULT NULL,
+	ADD INDEX `content_hash` (`content_hash`);
@@ -0,0 +1,4 @@
+-- 64-bit difference hash (dHash) of the image used to find visually
+-- similar photos: resized copies, re-encoded exports and bursts.
+ALTER TABLE `photos`
+	ADD COLUMN `phash` BIGINT UNSIGNED NULL DEFAULT NULL;
@@ -0,0 +1,17 @@
+-- Manually curated, ordered photo collections
+CREATE TABLE `albums` (
+	`id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+	`title` VARCHAR(255) NOT NULL,
+	`description` TEXT NOT NULL,
+	`cover_photo` BIGINT UNSIGNED NULL DEFAULT NULL,
+	`created_at` TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY (`id`)
+);
+
+CREATE TABLE `album_photos` (
+	`album` BIGINT UNSIGNED NOT NULL,
+	`photo` BIGINT UNSIGNED NOT NULL,
+	`position` INT UNSIGNED NOT NULL,
+	PRIMARY KEY (`album`, `photo`),
+	INDEX `album_position` (`album`, `position`)
+);
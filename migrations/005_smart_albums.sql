@@ -0,0 +1,12 @@
+-- Camera used to take the photo ("Make Model" from EXIF)
+ALTER TABLE `photos`
+	ADD COLUMN `exif_camera` VARCHAR(255) NULL DEFAULT NULL;
+
+-- Named saved searches evaluated live. `query` is JSON-serialized
+-- search::PhotoQuery.
+CREATE TABLE `smart_albums` (
+	`id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+	`title` VARCHAR(255) NOT NULL,
+	`query` TEXT NOT NULL,
+	PRIMARY KEY (`id`)
+);
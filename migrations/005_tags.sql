@@ -0,0 +1,15 @@
+-- Tags assigned to photos
+CREATE TABLE `tags` (
+	`id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+	`name` VARCHAR(255) NOT NULL,
+	PRIMARY KEY (`id`),
+	UNIQUE KEY `name` (`name`)
+);
+
+CREATE TABLE `photo_tags` (
+	`photo` BIGINT UNSIGNED NOT NULL,
+	`tag` BIGINT UNSIGNED NOT NULL,
+	`created_at` TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY (`photo`, `tag`),
+	INDEX `tag` (`tag`)
+);
@@ -0,0 +1,17 @@
+-- Public read-only links to an album, a search query or a list of photos.
+-- Exactly one of `album`, `query` (JSON search::PhotoQuery) and
+-- `photo_ids` (comma-separated) is set.
+CREATE TABLE `shares` (
+	`id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+	`token` CHAR(64) NOT NULL,
+	`album` BIGINT UNSIGNED NULL DEFAULT NULL,
+	`query` TEXT NULL DEFAULT NULL,
+	`photo_ids` TEXT NULL DEFAULT NULL,
+	`expires_at` DATETIME NULL DEFAULT NULL,
+	`password_hash` CHAR(64) NULL DEFAULT NULL,
+	`password_salt` CHAR(32) NULL DEFAULT NULL,
+	`allow_originals` TINYINT(1) NOT NULL DEFAULT 0,
+	`created_at` TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY (`id`),
+	UNIQUE KEY `token` (`token`)
+);
@@ -0,0 +1,4 @@
+-- Where tag assignment came from: 'manual' (set by user) or 'import'
+-- (keywords read from XMP sidecars and embedded IPTC/XMP metadata).
+ALTER TABLE `photo_tags`
+	ADD COLUMN `origin` VARCHAR(16) NOT NULL DEFAULT 'manual';
@@ -0,0 +1,40 @@
+-- Tag names become unique case- and accent-insensitively. Tags equal under
+-- the new collation, e.g. "cafe" and "Café", are merged into the oldest one
+-- first, otherwise the unique key couldn't be kept.
+CREATE TEMPORARY TABLE `tag_canonical` AS
+	SELECT tags.id AS `id`, (
+		SELECT MIN(other.id) FROM `tags` AS other
+		WHERE CONVERT(other.name USING utf8mb4) COLLATE utf8mb4_unicode_ci =
+			CONVERT(tags.name USING utf8mb4) COLLATE utf8mb4_unicode_ci
+	) AS `canonical`
+	FROM `tags`;
+
+INSERT IGNORE INTO `photo_tags` (`photo`, `tag`, `created_at`, `origin`)
+	SELECT photo_tags.photo, tag_canonical.canonical,
+	photo_tags.created_at, photo_tags.origin
+	FROM `photo_tags`
+	JOIN `tag_canonical` ON tag_canonical.id = photo_tags.tag
+	WHERE tag_canonical.id <> tag_canonical.canonical;
+
+DELETE photo_tags FROM `photo_tags`
+	JOIN `tag_canonical` ON tag_canonical.id = photo_tags.tag
+	WHERE tag_canonical.id <> tag_canonical.canonical;
+
+DELETE tags FROM `tags`
+	JOIN `tag_canonical` ON tag_canonical.id = tags.id
+	WHERE tag_canonical.id <> tag_canonical.canonical;
+
+DROP TEMPORARY TABLE `tag_canonical`;
+
+ALTER TABLE `tags`
+	MODIFY `name` VARCHAR(255) CHARACTER SET utf8mb4
+	COLLATE utf8mb4_unicode_ci NOT NULL;
+
+-- Alternative names resolving to a canonical tag in search and tagging
+CREATE TABLE `tag_aliases` (
+	`alias` VARCHAR(255) CHARACTER SET utf8mb4
+		COLLATE utf8mb4_unicode_ci NOT NULL,
+	`tag` BIGINT UNSIGNED NOT NULL,
+	PRIMARY KEY (`alias`),
+	INDEX `tag` (`tag`)
+);
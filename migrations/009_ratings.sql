@@ -0,0 +1,8 @@
+-- Culling marks: 0-5 stars, favorite flag and color label like "red"
+ALTER TABLE `photos`
+	ADD COLUMN `rating` TINYINT UNSIGNED NOT NULL DEFAULT 0,
+	ADD COLUMN `favorite` TINYINT(1) NOT NULL DEFAULT 0,
+	ADD COLUMN `color_label` VARCHAR(16) NULL,
+	ADD INDEX `rating` (`rating`),
+	ADD INDEX `favorite` (`favorite`),
+	ADD INDEX `color_label` (`color_label`);
@@ -0,0 +1,8 @@
+-- Trash: photos and sources with deleted_at set are hidden everywhere
+-- until restored or purged
+ALTER TABLE `photos`
+	ADD COLUMN `deleted_at` DATETIME NULL,
+	ADD INDEX `deleted_at` (`deleted_at`);
+
+ALTER TABLE `sources`
+	ADD COLUMN `deleted_at` DATETIME NULL;
@@ -0,0 +1,5 @@
+-- Display name of the source and flag to hide it temporarily, e.g. while
+-- the disk it lives on is unmounted
+ALTER TABLE `sources`
+	ADD COLUMN `name` VARCHAR(255) NULL,
+	ADD COLUMN `enabled` TINYINT(1) NOT NULL DEFAULT 1;
@@ -0,0 +1,93 @@
+-- A file is stored once per source, so crawling a source again is
+-- idempotent. Existing duplicate rows are merged into the oldest one.
+-- Paths are compared byte by byte: "IMG.jpg" and "img.JPG" are different
+-- files.
+CREATE TEMPORARY TABLE `photo_canonical` AS
+	SELECT photos.id AS `id`, (
+		SELECT MIN(other.id) FROM `photos` AS other
+		WHERE other.source = photos.source AND
+		BINARY other.relative_path = BINARY photos.relative_path
+	) AS `canonical`
+	FROM `photos`;
+
+DELETE FROM `photo_canonical` WHERE `id` = `canonical`;
+
+INSERT IGNORE INTO `photo_tags` (`photo`, `tag`, `created_at`, `origin`)
+	SELECT photo_canonical.canonical, photo_tags.tag,
+	photo_tags.created_at, photo_tags.origin
+	FROM `photo_tags`
+	JOIN `photo_canonical` ON photo_canonical.id = photo_tags.photo;
+
+DELETE photo_tags FROM `photo_tags`
+	JOIN `photo_canonical` ON photo_canonical.id = photo_tags.photo;
+
+INSERT IGNORE INTO `album_photos` (`album`, `photo`, `position`)
+	SELECT album_photos.album, photo_canonical.canonical, album_photos.position
+	FROM `album_photos`
+	JOIN `photo_canonical` ON photo_canonical.id = album_photos.photo;
+
+DELETE album_photos FROM `album_photos`
+	JOIN `photo_canonical` ON photo_canonical.id = album_photos.photo;
+
+UPDATE `albums`
+	JOIN `photo_canonical` ON photo_canonical.id = albums.cover_photo
+	SET albums.cover_photo = photo_canonical.canonical;
+
+UPDATE `photos`
+	JOIN `photo_canonical` ON photo_canonical.id = photos.duplicate_of
+	SET photos.duplicate_of = photo_canonical.canonical;
+
+-- Shares keep comma-separated ids, they're split into rows to be remapped.
+-- `photo_ids` is TEXT of at most 65535 bytes, so no list has more than
+-- 32768 ids and 100000 positions cover every share completely.
+CREATE TABLE `migration_digits` (`d` INT UNSIGNED NOT NULL, PRIMARY KEY (`d`));
+INSERT INTO `migration_digits` (`d`) VALUES (0), (1), (2), (3), (4), (5), (6), (7), (8), (9);
+
+CREATE TABLE `migration_numbers` (`n` INT UNSIGNED NOT NULL, PRIMARY KEY (`n`));
+INSERT INTO `migration_numbers` (`n`)
+	SELECT 1 + a.d + 10 * b.d + 100 * c.d + 1000 * d.d + 10000 * e.d
+	FROM `migration_digits` AS a, `migration_digits` AS b, `migration_digits` AS c,
+	`migration_digits` AS d, `migration_digits` AS e;
+
+CREATE TEMPORARY TABLE `share_photo_ids` AS
+	SELECT shares.id AS `share`, migration_numbers.n AS `position`,
+	CAST(SUBSTRING_INDEX(SUBSTRING_INDEX(shares.photo_ids, ',', migration_numbers.n),
+		',', -1) AS UNSIGNED) AS `photo`
+	FROM `shares`
+	JOIN `migration_numbers` ON migration_numbers.n <=
+		1 + LENGTH(shares.photo_ids) - LENGTH(REPLACE(shares.photo_ids, ',', ''))
+	WHERE shares.photo_ids IS NOT NULL;
+
+CREATE TEMPORARY TABLE `remapped_shares` AS
+	SELECT DISTINCT share_photo_ids.share AS `share`
+	FROM `share_photo_ids`
+	JOIN `photo_canonical` ON photo_canonical.id = share_photo_ids.photo;
+
+UPDATE `share_photo_ids`
+	JOIN `photo_canonical` ON photo_canonical.id = share_photo_ids.photo
+	SET share_photo_ids.photo = photo_canonical.canonical;
+
+SET SESSION group_concat_max_len = 1048576;
+UPDATE `shares`
+	JOIN `remapped_shares` ON remapped_shares.share = shares.id
+	SET shares.photo_ids = (
+		SELECT GROUP_CONCAT(share_photo_ids.photo ORDER BY share_photo_ids.position)
+		FROM `share_photo_ids` WHERE share_photo_ids.share = shares.id
+	);
+
+DROP TEMPORARY TABLE `remapped_shares`;
+DROP TEMPORARY TABLE `share_photo_ids`;
+DROP TABLE `migration_numbers`;
+DROP TABLE `migration_digits`;
+
+DELETE photos FROM `photos`
+	JOIN `photo_canonical` ON photo_canonical.id = photos.id;
+
+DROP TEMPORARY TABLE `photo_canonical`;
+
+-- Paths are unique by their SHA-256, which keeps the key short for long
+-- paths and compares them byte by byte regardless of collation
+ALTER TABLE `photos`
+	ADD COLUMN `relative_path_hash` BINARY(32)
+		AS (UNHEX(SHA2(`relative_path`, 256))) STORED,
+	ADD UNIQUE INDEX `source_relative_path` (`source`, `relative_path_hash`);
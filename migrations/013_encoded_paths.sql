@@ -0,0 +1,68 @@
+-- Paths are stored with non-UTF-8 bytes and "%" escaped as "%XX", so
+-- existing "%" characters are escaped to keep their meaning.
+UPDATE `sources` SET `full_path` = REPLACE(`full_path`, '%', '%25');
+UPDATE `photos` SET `relative_path` = REPLACE(`relative_path`, '%', '%25');
+
+-- Files with non-UTF-8 names used to be stored with an empty path. They
+-- are indexed again on the next crawl, references to them are dropped.
+CREATE TEMPORARY TABLE `unnamed_photos` AS
+	SELECT `id` FROM `photos` WHERE `relative_path` = '';
+
+DELETE photo_tags FROM `photo_tags`
+	JOIN `unnamed_photos` ON unnamed_photos.id = photo_tags.photo;
+
+DELETE album_photos FROM `album_photos`
+	JOIN `unnamed_photos` ON unnamed_photos.id = album_photos.photo;
+
+UPDATE `albums`
+	JOIN `unnamed_photos` ON unnamed_photos.id = albums.cover_photo
+	SET albums.cover_photo = NULL;
+
+UPDATE `photos`
+	JOIN `unnamed_photos` ON unnamed_photos.id = photos.duplicate_of
+	SET photos.duplicate_of = NULL;
+
+-- Shares keep comma-separated ids, they're split into rows as in 012
+CREATE TABLE `migration_digits` (`d` INT UNSIGNED NOT NULL, PRIMARY KEY (`d`));
+INSERT INTO `migration_digits` (`d`) VALUES (0), (1), (2), (3), (4), (5), (6), (7), (8), (9);
+
+CREATE TABLE `migration_numbers` (`n` INT UNSIGNED NOT NULL, PRIMARY KEY (`n`));
+INSERT INTO `migration_numbers` (`n`)
+	SELECT 1 + a.d + 10 * b.d + 100 * c.d + 1000 * d.d + 10000 * e.d
+	FROM `migration_digits` AS a, `migration_digits` AS b, `migration_digits` AS c,
+	`migration_digits` AS d, `migration_digits` AS e;
+
+CREATE TEMPORARY TABLE `share_photo_ids` AS
+	SELECT shares.id AS `share`, migration_numbers.n AS `position`,
+	CAST(SUBSTRING_INDEX(SUBSTRING_INDEX(shares.photo_ids, ',', migration_numbers.n),
+		',', -1) AS UNSIGNED) AS `photo`
+	FROM `shares`
+	JOIN `migration_numbers` ON migration_numbers.n <=
+		1 + LENGTH(shares.photo_ids) - LENGTH(REPLACE(shares.photo_ids, ',', ''))
+	WHERE shares.photo_ids IS NOT NULL;
+
+CREATE TEMPORARY TABLE `cleaned_shares` AS
+	SELECT DISTINCT share_photo_ids.share AS `share`
+	FROM `share_photo_ids`
+	JOIN `unnamed_photos` ON unnamed_photos.id = share_photo_ids.photo;
+
+DELETE share_photo_ids FROM `share_photo_ids`
+	JOIN `unnamed_photos` ON unnamed_photos.id = share_photo_ids.photo;
+
+SET SESSION group_concat_max_len = 1048576;
+UPDATE `shares`
+	JOIN `cleaned_shares` ON cleaned_shares.share = shares.id
+	SET shares.photo_ids = IFNULL((
+		SELECT GROUP_CONCAT(share_photo_ids.photo ORDER BY share_photo_ids.position)
+		FROM `share_photo_ids` WHERE share_photo_ids.share = shares.id
+	), '');
+
+DROP TEMPORARY TABLE `cleaned_shares`;
+DROP TEMPORARY TABLE `share_photo_ids`;
+DROP TABLE `migration_numbers`;
+DROP TABLE `migration_digits`;
+
+DELETE photos FROM `photos`
+	JOIN `unnamed_photos` ON unnamed_photos.id = photos.id;
+
+DROP TEMPORARY TABLE `unnamed_photos`;
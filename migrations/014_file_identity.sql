@@ -0,0 +1,8 @@
+-- Device and inode of the original, so a file reached through several
+-- symlinks, within a source or shared between sources, is indexed once.
+-- Photos indexed earlier get their identity on the next crawl of their
+-- source.
+ALTER TABLE `photos`
+	ADD COLUMN `file_device` BIGINT UNSIGNED NULL DEFAULT NULL,
+	ADD COLUMN `file_inode` BIGINT UNSIGNED NULL DEFAULT NULL,
+	ADD UNIQUE INDEX `file_identity` (`file_device`, `file_inode`);
@@ -0,0 +1,4 @@
+-- Size of the photo as displayed, i.e. with EXIF Orientation applied
+ALTER TABLE `photos`
+	ADD COLUMN `width` INT UNSIGNED NULL DEFAULT NULL,
+	ADD COLUMN `height` INT UNSIGNED NULL DEFAULT NULL;
@@ -0,0 +1,5 @@
+-- Share passwords are hashed with PBKDF2-HMAC-SHA256 using
+-- `password_iterations` iterations. Passwords hashed before with salted
+-- SHA-256 have NULL there and are rehashed on the next successful access.
+ALTER TABLE `shares`
+	ADD COLUMN `password_iterations` INT UNSIGNED NULL DEFAULT NULL;
@@ -0,0 +1,5 @@
+-- Indexes narrowing photos related by capture time or location, used by
+-- tag suggestions before the exact distance is calculated.
+ALTER TABLE `photos`
+	ADD INDEX `exif_datetime` (`exif_datetime`),
+	ADD INDEX `exif_location` (`exif_latitude`, `exif_longitude`);
@@ -0,0 +1,440 @@
+// Standard library includes
+use std::str::FromStr;
+
+// Library includes
+use router::Router;
+use iron::prelude::*;
+use iron::status;
+use mysql as my;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+use search::{Pagination, visible, photos_response, param_string, param_list,
+	param_number};
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Album {
+	id: u64,
+	title: String,
+	description: String,
+	cover_photo: Option<u64>,
+	photos_count: u64
+}
+
+/// Provides all albums
+pub fn list_albums(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+	let albums = get_albums(&connection, 0);
+
+	let out_json = json!({
+		"albums": albums,
+	});
+
+	Ok(
+		Response::with(
+			(status::Ok, to_string_pretty(&out_json).unwrap())
+		)
+	)
+}
+
+/// Provides album details
+pub fn get_album(request: &mut Request) -> IronResult<Response> {
+	let album_id = album_id(request);
+	let connection = db::get_connection(request);
+
+	match get_albums(&connection, album_id).pop() {
+		Some(album) => {
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&album).unwrap())
+				)
+			)
+		},
+		None => Err(ApiError::not_found("album not found").into())
+	}
+}
+
+/// Creates new album
+///
+/// Accepts `title`, optional `description` and `cover_photo`.
+pub fn create_album(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+
+	let title = param_string(&params, "title").unwrap_or(String::new());
+	if title.is_empty() {
+		return Err(ApiError::bad_request("title should be set").into());
+	}
+	let description = param_string(&params, "description")
+		.unwrap_or(String::new());
+	let cover_photo = param_number::<u64>(&params, "cover_photo")
+		.map_err(|message| ApiError::bad_request(&message))?;
+
+	let result = connection.prep_exec(r"
+		INSERT INTO `albums`
+		        (`title`, `description`, `cover_photo`)
+		VALUES  (:title, :description, :cover_photo)",
+		params!{
+			"title" => title,
+			"description" => description,
+			"cover_photo" => cover_photo
+		}
+	);
+
+	match result {
+		Ok(result) => {
+			let out_json = json!({
+				"status": "ok",
+				"id": result.last_insert_id()
+			});
+			Ok(
+				Response::with(
+					(status::Created, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Updates title, description or cover photo of the album
+///
+/// Only provided fields are changed. `cover_photo` = 0 resets cover to
+/// the first photo of the album.
+pub fn update_album(request: &mut Request) -> IronResult<Response> {
+	let album_id = album_id(request);
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+
+	let title = param_string(&params, "title");
+	let description = param_string(&params, "description");
+	let cover_photo = param_number::<u64>(&params, "cover_photo")
+		.map_err(|message| ApiError::bad_request(&message))?;
+
+	let result = connection.prep_exec(r"
+		UPDATE `albums`
+		SET   `title` = IFNULL(:title, `title`),
+		      `description` = IFNULL(:description, `description`),
+		      `cover_photo` = IF(:cover_photo IS NULL, `cover_photo`,
+		                         NULLIF(:cover_photo_id, 0))
+		WHERE `id` = :id",
+		params!{
+			"id" => album_id,
+			"title" => title,
+			"description" => description,
+			"cover_photo" => cover_photo,
+			"cover_photo_id" => cover_photo
+		}
+	);
+
+	match result {
+		Ok(ref result) if result.affected_rows() == 0 &&
+			!album_exists(&connection, album_id) => {
+			Err(ApiError::not_found("album not found").into())
+		},
+		Ok(_) => {
+			let out_json = json!({
+				"status": "ok"
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Deletes album. Photos themselves stay untouched.
+pub fn delete_album(request: &mut Request) -> IronResult<Response> {
+	let album_id = album_id(request);
+	let connection = db::get_connection(request);
+
+	let mut transaction = match connection.start_transaction(false, None, None) {
+		Ok(transaction) => transaction,
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	let _result = transaction.prep_exec(r"
+		DELETE FROM `album_photos` WHERE `album` = :id",
+		params!{"id" => album_id});
+	let deleted = match transaction.prep_exec(r"
+		DELETE FROM `albums` WHERE `id` = :id",
+		params!{"id" => album_id}) {
+		Ok(result) => result.affected_rows(),
+		Err(_) => 0
+	};
+
+	if deleted == 0 {
+		return Err(ApiError::not_found("album not found").into());
+	}
+
+	match transaction.commit() {
+		Ok(_) => {
+			let out_json = json!({
+				"status": "ok"
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Provides ids of photos in the album in their manual order
+///
+/// Response has the same format and pagination as /api/list_photos.
+pub fn list_album_photos(request: &mut Request) -> IronResult<Response> {
+	let album_id = album_id(request);
+	let connection = db::get_connection(request);
+
+	let params = request_params(request)?;
+	let pagination = Pagination::from_params(&params);
+
+	if !album_exists(&connection, album_id) {
+		return Err(ApiError::not_found("album not found").into());
+	}
+
+	let ids = get_album_photos(&connection, album_id).map_err(ApiError::from)?;
+	let total = ids.len() as u64;
+
+	Ok(photos_response(pagination.apply(&ids), total, &pagination))
+}
+
+/// Appends photos to the end of the album
+///
+/// Accepts `photo_ids` as comma-separated list or array. Photos may belong
+/// to any source. Photos already in the album keep their position.
+pub fn add_photos(request: &mut Request) -> IronResult<Response> {
+	let album_id = album_id(request);
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+	let photo_ids: Vec<u64> = param_list(&params, "photo_ids").iter()
+		.filter_map(|id| u64::from_str(id).ok())
+		.collect();
+
+	if !album_exists(&connection, album_id) {
+		return Err(ApiError::not_found("album not found").into());
+	}
+
+	let mut transaction = match connection.start_transaction(false, None, None) {
+		Ok(transaction) => transaction,
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	let mut added: u64 = 0;
+	for photo_id in photo_ids.iter() {
+		let result = transaction.prep_exec(r"
+			INSERT IGNORE INTO `album_photos` (`album`, `photo`, `position`)
+			SELECT :album, photos.id, (
+				SELECT IFNULL(MAX(`position`) + 1, 0) FROM `album_photos`
+				WHERE `album` = :album_id
+			)
+			FROM `photos` WHERE photos.id = :photo",
+			params!{
+				"album" => album_id,
+				"album_id" => album_id,
+				"photo" => photo_id
+			}
+		);
+
+		match result {
+			Ok(result) => added += result.affected_rows(),
+			Err(err) => println!("{:?}", err)
+		}
+	}
+
+	match transaction.commit() {
+		Ok(_) => {
+			let out_json = json!({
+				"status": "ok",
+				"added": added
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Removes photos from the album
+pub fn remove_photos(request: &mut Request) -> IronResult<Response> {
+	let album_id = album_id(request);
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+	let photo_ids: Vec<u64> = param_list(&params, "photo_ids").iter()
+		.filter_map(|id| u64::from_str(id).ok())
+		.collect();
+
+	let ids: Vec<String> = photo_ids.iter().map(|id| id.to_string()).collect();
+
+	let result = connection.prep_exec(r"
+		DELETE FROM `album_photos`
+		WHERE `album` = :album AND FIND_IN_SET(`photo`, :photo_ids)",
+		params!{
+			"album" => album_id,
+			"photo_ids" => ids.join(",")
+		}
+	);
+
+	match result {
+		Ok(result) => {
+			let out_json = json!({
+				"status": "ok",
+				"removed": result.affected_rows()
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Changes manual order of photos in the album
+///
+/// Accepts `photo_ids` in the desired order. Album photos missing from
+/// the list are moved after the listed ones keeping their relative order.
+pub fn reorder_photos(request: &mut Request) -> IronResult<Response> {
+	let album_id = album_id(request);
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+	let photo_ids: Vec<u64> = param_list(&params, "photo_ids").iter()
+		.filter_map(|id| u64::from_str(id).ok())
+		.collect();
+
+	if !album_exists(&connection, album_id) {
+		return Err(ApiError::not_found("album not found").into());
+	}
+
+	let current = get_album_photos(&connection, album_id).map_err(ApiError::from)?;
+
+	let mut order: Vec<u64> = vec![];
+	for id in photo_ids.iter() {
+		if current.contains(id) && !order.contains(id) {
+			order.push(*id);
+		}
+	}
+	for id in current.iter() {
+		if !order.contains(id) {
+			order.push(*id);
+		}
+	}
+
+	let mut transaction = match connection.start_transaction(false, None, None) {
+		Ok(transaction) => transaction,
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	for (position, photo_id) in order.iter().enumerate() {
+		let result = transaction.prep_exec(r"
+			UPDATE `album_photos` SET `position` = :position
+			WHERE `album` = :album AND `photo` = :photo",
+			params!{
+				"position" => position as u64,
+				"album" => album_id,
+				"photo" => photo_id
+			}
+		);
+
+		if let Err(err) = result {
+			return Err(ApiError::from(err).into());
+		}
+	}
+
+	match transaction.commit() {
+		Ok(_) => {
+			let out_json = json!({
+				"photos": order,
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Returns ids of photos in the album ordered by position
+pub fn get_album_photos(connection: &my::Pool, album_id: u64)
+	-> Result<Vec<u64>, my::Error> {
+	let mut ids: Vec<u64> = vec![];
+	for row in connection.prep_exec(format!(r"
+		SELECT album_photos.photo FROM `album_photos`, `photos`
+		WHERE album_photos.album = :album AND photos.id = album_photos.photo
+		AND {}
+		ORDER BY album_photos.position, album_photos.photo",
+		visible("photos")),
+		params!{"album" => album_id})? {
+		ids.push(my::from_row(row?));
+	}
+	Ok(ids)
+}
+
+/// Loads albums from DB. Album id = 0 loads all albums.
+///
+/// If cover photo wasn't chosen, the first photo of the album is used.
+fn get_albums(connection: &my::Pool, album_id: u64) -> Vec<Album> {
+	let result = connection.prep_exec(r"
+		SELECT albums.id, albums.title, albums.description,
+		IFNULL(albums.cover_photo, (
+			SELECT `photo` FROM `album_photos`
+			WHERE `album` = albums.id
+			ORDER BY `position`, `photo` LIMIT 1
+		)),
+		(SELECT COUNT(*) FROM `album_photos` WHERE `album` = albums.id)
+		FROM `albums`
+		WHERE :album_id = 0 OR albums.id = :id
+		ORDER BY albums.id",
+		params!{"album_id" => album_id, "id" => album_id}
+	);
+
+	let mut albums: Vec<Album> = vec![];
+	match result {
+		Ok(result) => {
+			result.for_each(|row| {
+				match row {
+					Ok(row) => {
+						let (id, title, description, cover_photo, photos_count) =
+							my::from_row(row);
+						albums.push(Album {
+							id: id,
+							title: title,
+							description: description,
+							cover_photo: cover_photo,
+							photos_count: photos_count
+						});
+					},
+					Err(_) => {}
+				}
+			});
+		},
+		Err(err) => println!("{:?}", err)
+	}
+	albums
+}
+
+fn album_exists(connection: &my::Pool, album_id: u64) -> bool {
+	album_id != 0 && !get_albums(connection, album_id).is_empty()
+}
+
+/// Reads :id url segment
+fn album_id(request: &Request) -> u64 {
+	request.extensions.get::<Router>().unwrap()
+		.find("id").unwrap_or("0")
+		.parse::<u64>().unwrap_or(0)
+}
+
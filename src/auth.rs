@@ -1,12 +1,31 @@
+// Library includes
+use iron::prelude::*;
+use iron::status;
+use serde_json::to_string_pretty;
+
 pub fn login(request: &mut Request) -> IronResult<Response> {
 	/// Checks provided credentials and if correct generates access token.
 	// TODO: Victor Semenov: implement logic
 
-	Ok(Response::with((status::Ok, "ok")))
+	let out_json = json!({
+		"status": "ok"
+	});
+	Ok(
+		Response::with(
+			(status::Ok, to_string_pretty(&out_json).unwrap())
+		)
+	)
 }
 
 pub fn set_password(request: &mut Request) -> IronResult<Response> {
 	/// Allows to set user password if not set yet
 
-	Ok(Response::with((status::Ok, "ok")))
+	let out_json = json!({
+		"status": "ok"
+	});
+	Ok(
+		Response::with(
+			(status::Ok, to_string_pretty(&out_json).unwrap())
+		)
+	)
 }
\ No newline at end of file
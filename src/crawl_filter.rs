@@ -0,0 +1,519 @@
+// Standard library includes
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+// Library includes
+use regex;
+use regex::Regex;
+use walkdir::{DirEntry, WalkDir};
+
+// Local includes
+use paths::PathPolicy;
+
+/// Name of per-directory ignore files
+pub const IGNORE_FILE: &'static str = ".galleryignore";
+
+/// Rules deciding which files are crawled
+///
+/// Configured in settings:
+/// * `crawl_follow_symlinks` - "true" to enter symlinked directories,
+///   links leading back to their own ancestors are reported and skipped.
+///   Symlinks leading outside of `source_roots` are always skipped.
+/// * `crawl_same_filesystem` - "true" to stay on the file system of the
+///   source, mount points inside of it aren't entered
+/// * `crawl_ignore` - gitignore-style globs separated by commas or new
+///   lines, applied as if they were in `.galleryignore` at the source root.
+///   For example "@eaDir, .Trash-*, *_thumb.jpg".
+/// * `crawl_skip_hidden` - "false" to crawl hidden files and directories
+///   (names starting with a dot), skipped by default
+/// * `crawl_min_size` - minimum file size in bytes
+/// * `crawl_min_width`, `crawl_min_height` - minimum image dimensions in
+///   pixels
+///
+/// Besides that, `.galleryignore` in any directory of a source lists
+/// gitignore-style globs applied to that directory and everything below it.
+#[derive(Debug, Clone)]
+pub struct CrawlFilter {
+	policy: PathPolicy,
+	follow_symlinks: bool,
+	same_filesystem: bool,
+	ignore: IgnoreRules,
+	skip_hidden: bool,
+	min_size: u64,
+	min_width: u32,
+	min_height: u32
+}
+
+impl CrawlFilter {
+	pub fn from_settings(settings: &HashMap<String, String>) -> CrawlFilter {
+		let number = |name: &str| {
+			settings.get(name).and_then(|value| value.trim().parse::<u64>().ok()).unwrap_or(0)
+		};
+		let enabled = |name: &str| settings.get(name).map(|value| value == "true").unwrap_or(false);
+
+		let globs = settings.get("crawl_ignore")
+			.map(|value| value.replace(',', "\n"))
+			.unwrap_or(String::new());
+
+		CrawlFilter {
+			policy: PathPolicy::from_settings(settings),
+			follow_symlinks: enabled("crawl_follow_symlinks"),
+			same_filesystem: enabled("crawl_same_filesystem"),
+			ignore: IgnoreRules::parse(&globs),
+			skip_hidden: settings.get("crawl_skip_hidden")
+				.map(|value| value != "false")
+				.unwrap_or(true),
+			min_size: number("crawl_min_size"),
+			min_width: number("crawl_min_width") as u32,
+			min_height: number("crawl_min_height") as u32
+		}
+	}
+
+	/// Walker over the source at `root` following configured links and
+	/// file systems
+	pub fn walk_dir(&self, root: &Path) -> WalkDir {
+		WalkDir::new(root)
+			.follow_links(self.follow_symlinks)
+			.same_file_system(self.same_filesystem)
+	}
+
+	/// Starts filtering of a walk over the source at `root`
+	pub fn walk(&self, root: &Path) -> IgnoreStack {
+		IgnoreStack {
+			filter: self.clone(),
+			root: root.to_path_buf(),
+			frames: vec![]
+		}
+	}
+
+	/// Checks size and dimensions of a file which passed ignore rules
+	pub fn accepts_file(&self, path: &Path, size: u64) -> bool {
+		if size < self.min_size {
+			return false;
+		}
+		if self.min_width == 0 && self.min_height == 0 {
+			return true;
+		}
+
+		match jpeg_dimensions(path) {
+			Some((width, height)) => width >= self.min_width && height >= self.min_height,
+			None => {
+				println!("Unable to read dimensions of {:?}", path);
+				true
+			}
+		}
+	}
+}
+
+/// Ignore rules in effect at the current position of a walk
+///
+/// Walk visits directories depth-first, so rules of a directory are kept
+/// on the stack while its entries are visited.
+pub struct IgnoreStack {
+	filter: CrawlFilter,
+	root: PathBuf,
+	frames: Vec<IgnoreFrame>
+}
+
+/// Rules of `.galleryignore` found in `base` at walk depth `depth`
+struct IgnoreFrame {
+	depth: usize,
+	base: PathBuf,
+	rules: IgnoreRules
+}
+
+impl IgnoreStack {
+	/// Decides whether the entry should be crawled
+	///
+	/// Used as `filter_entry` predicate, so skipped directories aren't
+	/// entered at all.
+	pub fn keep(&mut self, entry: &DirEntry) -> bool {
+		let depth = entry.depth();
+		while self.frames.last().map(|frame| frame.depth >= depth).unwrap_or(false) {
+			self.frames.pop();
+		}
+
+		let is_dir = entry.file_type().is_dir();
+		if depth > 0 && self.is_ignored(entry.path(), is_dir) {
+			return false;
+		}
+
+		// Links may lead anywhere, only targets inside of source roots
+		// are crawled
+		if entry.path_is_symlink() && !self.filter.policy.is_within_roots(entry.path()) {
+			println!("Skipping {:?} leading outside of source roots", entry.path());
+			return false;
+		}
+
+		if is_dir {
+			if let Ok(content) = fs::read_to_string(entry.path().join(IGNORE_FILE)) {
+				self.frames.push(IgnoreFrame {
+					depth: depth,
+					base: entry.path().to_path_buf(),
+					rules: IgnoreRules::parse(&content)
+				});
+			}
+		}
+		true
+	}
+
+	fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+		let hidden = path.file_name()
+			.map(|name| name.to_string_lossy().starts_with('.'))
+			.unwrap_or(false);
+		if hidden && self.filter.skip_hidden {
+			return true;
+		}
+
+		// Deeper rules override outer ones, the last matching rule wins
+		let mut ignored = self.filter.ignore.matches(&relative(&self.root, path), is_dir);
+		for frame in self.frames.iter() {
+			if let Some(matched) = frame.rules.matches(&relative(&frame.base, path), is_dir) {
+				ignored = Some(matched);
+			}
+		}
+		ignored.unwrap_or(false)
+	}
+}
+
+/// Parsed gitignore-style globs
+#[derive(Debug, Clone)]
+struct IgnoreRules {
+	rules: Vec<IgnoreRule>
+}
+
+#[derive(Debug, Clone)]
+struct IgnoreRule {
+	regex: Regex,
+	/// Rule starting with "!" includes back what previous rules ignored
+	negated: bool,
+	/// Rule ending with "/" matches directories only
+	dir_only: bool
+}
+
+impl IgnoreRules {
+	/// Parses one glob per line. Empty lines and lines starting with "#"
+	/// are skipped, invalid globs are reported and skipped.
+	fn parse(content: &str) -> IgnoreRules {
+		let mut rules: Vec<IgnoreRule> = vec![];
+
+		for line in content.lines() {
+			let mut glob = line.trim();
+			if glob.is_empty() || glob.starts_with('#') {
+				continue;
+			}
+
+			let negated = glob.starts_with('!');
+			if negated {
+				glob = &glob[1..];
+			} else if glob.starts_with('\\') {
+				glob = &glob[1..];
+			}
+
+			let dir_only = glob.ends_with('/');
+			let glob = glob.trim_end_matches('/');
+			// Glob with a slash is relative to the ignore file location,
+			// otherwise it matches names at any depth
+			let anchored = glob.contains('/');
+			let glob = glob.trim_start_matches('/');
+			if glob.is_empty() {
+				continue;
+			}
+
+			let pattern = if anchored {
+				format!("^{}$", glob_to_regex(glob))
+			} else {
+				format!("^(?:.*/)?{}$", glob_to_regex(glob))
+			};
+
+			match Regex::new(&pattern) {
+				Ok(regex) => rules.push(IgnoreRule {
+					regex: regex,
+					negated: negated,
+					dir_only: dir_only
+				}),
+				Err(err) => println!("Invalid ignore glob {:?}: {}", line, err)
+			}
+		}
+
+		IgnoreRules { rules: rules }
+	}
+
+	/// Returns whether the last matching rule ignores the path, None if no
+	/// rule matches
+	fn matches(&self, relative_path: &str, is_dir: bool) -> Option<bool> {
+		self.rules.iter()
+			.filter(|rule| is_dir || !rule.dir_only)
+			.filter(|rule| rule.regex.is_match(relative_path))
+			.last()
+			.map(|rule| !rule.negated)
+	}
+}
+
+/// Converts glob to regex: "*" and "?" don't cross "/", "**" does
+fn glob_to_regex(glob: &str) -> String {
+	let chars: Vec<char> = glob.chars().collect();
+	let mut result = String::new();
+	let mut index = 0;
+
+	while index < chars.len() {
+		match chars[index] {
+			'*' if chars.get(index + 1) == Some(&'*') => {
+				index += 1;
+				if chars.get(index + 1) == Some(&'/') {
+					// "**/" matches zero or more directories
+					index += 1;
+					result.push_str("(?:.*/)?");
+				} else {
+					result.push_str(".*");
+				}
+			},
+			'*' => result.push_str("[^/]*"),
+			'?' => result.push_str("[^/]"),
+			'[' => {
+				match chars[index..].iter().position(|&c| c == ']') {
+					Some(end) if end > 1 => {
+						let class: String = chars[index + 1..index + end].iter().collect();
+						let class = class.replace('\\', "\\\\");
+						if class.starts_with('!') {
+							result.push_str(&format!("[^{}]", &class[1..]));
+						} else {
+							result.push_str(&format!("[{}]", class));
+						}
+						index += end;
+					},
+					_ => result.push_str("\\[")
+				}
+			},
+			c => result.push_str(&regex::escape(&c.to_string()))
+		}
+		index += 1;
+	}
+
+	result
+}
+
+/// Path relative to `base` with "/" separators
+fn relative(base: &Path, path: &Path) -> String {
+	path.strip_prefix(base)
+		.unwrap_or(path)
+		.components()
+		.map(|component| component.as_os_str().to_string_lossy().into_owned())
+		.collect::<Vec<String>>()
+		.join("/")
+}
+
+/// Reads width and height from the JPEG frame header
+pub fn jpeg_dimensions(path: &Path) -> Option<(u32, u32)> {
+	let file = File::open(path).ok()?;
+	let mut reader = BufReader::new(file);
+	let mut byte = [0u8; 1];
+
+	let mut marker = [0u8; 2];
+	reader.read_exact(&mut marker).ok()?;
+	if marker != [0xFF, 0xD8] {
+		return None;
+	}
+
+	loop {
+		// Markers may be padded with any number of 0xFF
+		reader.read_exact(&mut byte).ok()?;
+		if byte[0] != 0xFF {
+			return None;
+		}
+		while byte[0] == 0xFF {
+			reader.read_exact(&mut byte).ok()?;
+		}
+		let marker = byte[0];
+
+		// Standalone markers have no length
+		if marker == 0x01 || (marker >= 0xD0 && marker <= 0xD7) {
+			continue;
+		}
+
+		let mut length = [0u8; 2];
+		reader.read_exact(&mut length).ok()?;
+		let length = ((length[0] as usize) << 8 | length[1] as usize).checked_sub(2)?;
+
+		// Start of frame, except DHT, JPG and DAC which share the range
+		if marker >= 0xC0 && marker <= 0xCF &&
+			marker != 0xC4 && marker != 0xC8 && marker != 0xCC {
+			let mut frame = [0u8; 5];
+			reader.read_exact(&mut frame).ok()?;
+			let height = (frame[1] as u32) << 8 | frame[2] as u32;
+			let width = (frame[3] as u32) << 8 | frame[4] as u32;
+			return Some((width, height));
+		}
+
+		let mut segment = vec![0u8; length];
+		reader.read_exact(&mut segment).ok()?;
+	}
+}
+
+#[cfg(test)]
+pub mod tests {
+	use std::collections::HashMap;
+	use std::fs;
+	use std::fs::File;
+	use std::os::unix::fs::symlink;
+	use std::path::{Path, PathBuf};
+	use std::process;
+
+	use super::{CrawlFilter, IgnoreRules, IGNORE_FILE};
+
+	fn ignored(globs: &str, path: &str, is_dir: bool) -> bool {
+		IgnoreRules::parse(globs).matches(path, is_dir).unwrap_or(false)
+	}
+
+	/// Creates files at given relative paths under an empty temporary root
+	pub fn tree(name: &str, files: &[&str]) -> PathBuf {
+		let root = ::std::env::temp_dir()
+			.join(format!("gallery-filter-{}-{}", process::id(), name));
+		let _ = fs::remove_dir_all(&root);
+		for file in files.iter() {
+			let path = root.join(file);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			File::create(&path).unwrap();
+		}
+		root
+	}
+
+	/// Relative paths of files kept while walking the root
+	fn walk(root: &Path, settings: &HashMap<String, String>) -> Vec<String> {
+		let filter = CrawlFilter::from_settings(settings);
+		let mut ignore_stack = filter.walk(root);
+		let mut files: Vec<String> = filter.walk_dir(root).into_iter()
+			.filter_entry(|entry| ignore_stack.keep(entry))
+			.filter_map(|entry| entry.ok())
+			.filter(|entry| entry.file_type().is_file())
+			.filter(|entry| entry.file_name() != IGNORE_FILE)
+			.map(|entry| super::relative(root, entry.path()))
+			.collect();
+		files.sort();
+		files
+	}
+
+	#[test]
+	fn single_star_stays_within_directory() {
+		assert!(ignored("*.tmp", "a/b/photo.tmp", false));
+		assert!(ignored("a/*.jpg", "a/photo.jpg", false));
+		assert!(!ignored("a/*.jpg", "a/b/photo.jpg", false));
+	}
+
+	#[test]
+	fn double_star_crosses_directories() {
+		assert!(ignored("a/**/*.jpg", "a/photo.jpg", false));
+		assert!(ignored("a/**/*.jpg", "a/b/c/photo.jpg", false));
+		assert!(ignored("**/cache", "x/y/cache", true));
+		assert!(ignored("a/**", "a/b/photo.jpg", false));
+		assert!(!ignored("a/**/*.jpg", "b/photo.jpg", false));
+	}
+
+	#[test]
+	fn negation_includes_back() {
+		let globs = "*.jpg\n!keep.jpg";
+		assert!(ignored(globs, "photo.jpg", false));
+		assert!(!ignored(globs, "keep.jpg", false));
+		assert!(!ignored(globs, "sub/keep.jpg", false));
+		// Last matching rule wins
+		assert!(ignored("!keep.jpg\n*.jpg", "keep.jpg", false));
+	}
+
+	#[test]
+	fn anchored_globs_match_from_base_only() {
+		assert!(ignored("/raw", "raw", true));
+		assert!(!ignored("/raw", "2019/raw", true));
+		assert!(ignored("raw", "2019/raw", true));
+		assert!(ignored("2019/raw", "2019/raw", true));
+		assert!(!ignored("2019/raw", "old/2019/raw", true));
+	}
+
+	#[test]
+	fn directory_globs_skip_files() {
+		assert!(ignored("cache/", "cache", true));
+		assert!(!ignored("cache/", "cache", false));
+	}
+
+	#[test]
+	fn comments_and_escapes() {
+		assert!(!ignored("# photo.jpg", "# photo.jpg", false));
+		assert!(ignored("\\#photo.jpg", "#photo.jpg", false));
+		assert!(ignored("\\!photo.jpg", "!photo.jpg", false));
+	}
+
+	#[test]
+	fn nested_ignore_file_overrides_outer_rules() {
+		let root = tree("nested", &[
+			"a.jpg",
+			"b.tmp.jpg",
+			"keep/b.tmp.jpg",
+			"keep/c.jpg",
+			"skip/d.jpg"
+		]);
+		fs::write(root.join(IGNORE_FILE), "*.tmp.jpg\nskip/").unwrap();
+		fs::write(root.join("keep").join(IGNORE_FILE), "!*.tmp.jpg\nc.jpg").unwrap();
+
+		assert_eq!(walk(&root, &HashMap::new()), vec!["a.jpg", "keep/b.tmp.jpg"]);
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn settings_rules_apply_at_source_root() {
+		let root = tree("settings", &["a.jpg", "@eaDir/a.jpg", "sub/@eaDir/b.jpg"]);
+		let mut settings = HashMap::new();
+		settings.insert("crawl_ignore".to_string(), "@eaDir, *.png".to_string());
+
+		assert_eq!(walk(&root, &settings), vec!["a.jpg"]);
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	/// Settings following symlinks within the root
+	fn following(root: &Path) -> HashMap<String, String> {
+		let mut settings = HashMap::new();
+		settings.insert("crawl_follow_symlinks".to_string(), "true".to_string());
+		settings.insert("source_roots".to_string(), root.to_string_lossy().into_owned());
+		settings
+	}
+
+	#[test]
+	fn symlink_loops_are_skipped() {
+		let root = tree("loop", &["a.jpg", "sub/b.jpg"]);
+		symlink(&root, root.join("sub").join("up")).unwrap();
+
+		assert_eq!(walk(&root, &following(&root)), vec!["a.jpg", "sub/b.jpg"]);
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn symlinks_outside_of_source_roots_are_skipped() {
+		let root = tree("escape", &["a.jpg", "sub/b.jpg"]);
+		let outside = tree("escape-outside", &["c.jpg"]);
+		symlink(&outside, root.join("outside")).unwrap();
+		symlink(outside.join("c.jpg"), root.join("c.jpg")).unwrap();
+		symlink(root.join("sub"), root.join("inside")).unwrap();
+
+		assert_eq!(
+			walk(&root, &following(&root)),
+			vec!["a.jpg", "inside/b.jpg", "sub/b.jpg"]
+		);
+		fs::remove_dir_all(&root).unwrap();
+		fs::remove_dir_all(&outside).unwrap();
+	}
+
+	#[test]
+	fn hidden_entries_are_skipped_by_default() {
+		let root = tree("hidden", &["a.jpg", ".b.jpg", ".cache/c.jpg", "sub/.thumbs/d.jpg"]);
+		assert_eq!(walk(&root, &HashMap::new()), vec!["a.jpg"]);
+
+		let mut settings = HashMap::new();
+		settings.insert("crawl_skip_hidden".to_string(), "false".to_string());
+		assert_eq!(
+			walk(&root, &settings),
+			vec![".b.jpg", ".cache/c.jpg", "a.jpg", "sub/.thumbs/d.jpg"]
+		);
+		fs::remove_dir_all(&root).unwrap();
+	}
+}
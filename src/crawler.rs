@@ -1,53 +1,88 @@
 // Standard library includes
 use router::Router;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::slice;
 
 // Library includes
 use iron::prelude::*;
 use iron::status;
-use params::Params;
 use params::FromValue;
 use mysql as my;
-use walkdir::{DirEntry, WalkDir};
+use walkdir::DirEntry;
 use serde_json::to_string_pretty;
+use persistent::State;
 
 // Local includes
 use db;
+use error::{ApiError, request_params};
+use xmp;
+use folder_tags;
+use folder_tags::FolderTagRules;
+use trash;
+use crawler_pool::{CrawlerPoolShared, ProgressReporter};
+use crawl_filter::CrawlFilter;
+use paths::{PathPolicy, encode_path, decode_path};
+use Settings;
+use search::{PhotoQuery, Pagination, find_photos, param_string, photos_response};
 
 #[derive(Serialize, Deserialize)]
 struct SourcePath {
 	id: u32,
 	full_path: String,
-	status: String
+	status: String,
+	name: Option<String>,
+	enabled: bool
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct GalleryImage {
 	source_path: String,
 	relative_path: String,
-	size: u64
+	size: u64,
+	/// Device and inode of the file links lead to
+	device: u64,
+	inode: u64
+}
+
+/// Number of photos inserted by a single statement during crawl
+const CRAWL_BATCH: usize = 500;
+
+/// Outcome of saving crawled files to DB
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct CrawlSummary {
+	/// New photos saved
+	pub inserted: u64,
+	/// Photos which were already known or reached through another link
+	pub skipped: u64,
+	/// Files which couldn't be read or saved
+	pub failed: u64
 }
 
 /// Provides all available source paths
-pub fn list_source_paths(_request: &mut Request) -> IronResult<Response> {
+pub fn list_source_paths(request: &mut Request) -> IronResult<Response> {
 	println!("list_source_paths");
 
-	let connection = db::get_connection();
-	println!("list_source_paths1");
-	let result = connection.prep_exec(r"SELECT `id`,`full_path`, `status` FROM `sources`", ()).unwrap();
-	println!("list_source_paths2");
+	let connection = db::get_connection(request);
+	let result = match connection.prep_exec(r"SELECT `id`,`full_path`, `status`, `name`, `enabled` FROM `sources` WHERE `deleted_at` IS NULL", ()) {
+		Ok(result) => result,
+		Err(err) => return Err(ApiError::from(err).into())
+	};
 	let mut paths: Vec<SourcePath> = vec![];
 
 
 	result.for_each(|row| {
 		match row {
 			Ok(row) => {
-				let (id, full_path, status) = my::from_row(row);
+				let (id, full_path, status, name, enabled) = my::from_row(row);
 				paths.push(SourcePath{
 					id: id,
 					full_path: full_path,
-					status: status
+					status: status,
+					name: name,
+					enabled: enabled
 				});
 			},
 			Err(_) => {}
@@ -65,181 +100,708 @@ pub fn list_source_paths(_request: &mut Request) -> IronResult<Response> {
 	)
 }
 
+/// Provides ids of photos in the source :id
+///
+/// Accepts optional `page` and `per_page` parameters.
 pub fn list_photos(request: &mut Request) -> IronResult<Response> {
 	let ref id = request.extensions.get::<Router>().unwrap()
 	.find("id").unwrap_or("0");
 
 	let source_id = id.parse::<u64>().unwrap_or(0);
+	let connection = db::get_connection(request);
 
-	let mut ids: Vec<u64> = vec![];
+	let params = request_params(request)?;
+	let pagination = Pagination::from_params(&params);
 
-	for key in get_photos(source_id).keys() {
-		ids.push(*key);
+	let query = PhotoQuery {
+		source_id: Some(source_id),
+		..PhotoQuery::default()
+	};
+
+	match find_photos(&connection, &query, &pagination) {
+		Ok((ids, total)) => Ok(photos_response(ids, total, &pagination)),
+		Err(err) => Err(ApiError::from(err).into())
 	}
+}
 
-	let out_json = json!({
-		"photos": ids,
-	});
+/// Options of indexing new sources, read from settings
+#[derive(Debug)]
+pub struct CrawlOptions {
+	import_keywords: bool,
+	import_ratings: bool,
+	folder_tag_rules: Option<FolderTagRules>,
+	filter: CrawlFilter,
+	policy: PathPolicy
+}
 
-	Ok(
-		Response::with(
-			(status::Ok, to_string_pretty(&out_json).unwrap())
-		)
-	)
+impl CrawlOptions {
+	pub fn from_settings(settings: &HashMap<String, String>) -> CrawlOptions {
+		CrawlOptions {
+			import_keywords: settings.get("import_keywords")
+				.map(|value| value != "false")
+				.unwrap_or(true),
+			import_ratings: settings.get("import_ratings")
+				.map(|value| value != "false")
+				.unwrap_or(true),
+			folder_tag_rules: match settings.get("folder_tags").map(|value| value == "true") {
+				Some(true) => Some(FolderTagRules::from_settings(settings)),
+				_ => None
+			},
+			filter: CrawlFilter::from_settings(settings),
+			policy: PathPolicy::from_settings(settings)
+		}
+	}
 }
 
 /// Adds source path to the database.
 ///
-/// This function saves provided absolute path (on the server) to the database
-/// and goes over all jpeg files recursively in order to add them to DB.
+/// This function saves provided absolute path (on the server) to the
+/// database and queues crawling of it. Returns id of the new source right
+/// away; progress of crawling is available at /api/process_status.
 pub fn add_source_path(request: &mut Request) -> IronResult<Response> {
-	
-	let params = request.get_ref::<Params>().unwrap();
-
-	let path = &params["path"];
+	let path = {
+		let params = request_params(request)?;
+		params.find(&["path"])
+			.and_then(|path| String::from_value(path))
+			.unwrap_or(String::new())
+	};
+
+	if path.is_empty() {
+		return Err(ApiError::bad_request("path should be set").into());
+	}
+	let path = source_path(request, &path)?;
 
-	let connection = db::get_connection();
+	let connection = db::get_connection(request);
 	let result = connection.prep_exec(r"
 	     INSERT INTO `sources` 
 	             (`full_path`) 
 	     VALUES  (:path)", 
-	     params!{"path" => String::from_value(path)});
+	     params!{"path" => &path});
+
+	let source_id = match result {
+		Ok(result) => result.last_insert_id(),
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	let rwlock = request.get::<State<CrawlerPoolShared>>().unwrap();
+	let crawler_pool = rwlock.read().unwrap();
+
+	match crawler_pool.add_source_to_crawl(source_id, path) {
+		Ok(false) => Err(ApiError::conflict("source is already being crawled").into()),
+		Ok(true) => {
+			let out_json = json!({
+				"status": "accepted",
+				"source_id": source_id
+			});
+			Ok(
+				Response::with(
+					(status::Accepted, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::internal(err).into())
+	}
+}
+
+/// Crawls the source and imports metadata of found photos
+///
+/// Runs in CrawlerPool thread. Keywords from XMP sidecars and embedded
+/// IPTC/XMP metadata are imported as tags unless `import_keywords` setting
+/// is "false", star ratings and color labels unless `import_ratings`
+/// setting is "false". Tags from folder names are generated if
+/// `folder_tags` setting is "true". Files are skipped according to
+/// `crawl_*` settings and `.galleryignore` files, see `CrawlFilter`.
+pub fn index_source(connection: &my::Pool, source_id: u64, path: &str,
+	options: &CrawlOptions, progress: &ProgressReporter)
+	-> Result<CrawlSummary, &'static str> {
+	let summary = crawl_source(connection, path.to_string(), &source_id,
+		&options.filter, progress)?;
+
+	// Source was successfully crawled
+	let _result = connection.prep_exec(r"
+	     UPDATE `sources` 
+	     SET   `status` = 'indexed' 
+	     WHERE `id` = :source_id", 
+	     params!{"source_id" => &source_id});
+
+	progress.set_status("importing");
+	if options.import_keywords {
+		xmp::import_keywords(connection, &options.policy, source_id);
+	}
+	if options.import_ratings {
+		xmp::import_ratings(connection, &options.policy, source_id);
+	}
+	if let Some(ref rules) = options.folder_tag_rules {
+		match folder_tags::apply_to_source(connection, source_id, rules) {
+			Ok(_) => {},
+			Err(err) => println!("Unable to apply folder tags: {:?}", err)
+		}
+	}
+
+	Ok(summary)
+}
+
+/// Crawls the source :id again in background
+///
+/// Only files which are not in DB yet are added. Progress is available at
+/// /api/process_status. Fails with 409 if the source is already queued or
+/// being crawled.
+pub fn rescan_source(request: &mut Request) -> IronResult<Response> {
+	let source_id = source_id(request);
+	let connection = db::get_connection(request);
+
+	let path: String = match connection.prep_exec(r"
+		SELECT `full_path` FROM `sources` WHERE `id` = :id",
+		params!{"id" => source_id}) {
+		Ok(mut result) => {
+			match result.next() {
+				Some(Ok(row)) => my::from_row(row),
+				_ => return Err(ApiError::not_found("source not found").into())
+			}
+		},
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	// Source roots might have changed since the source was added
+	source_path(request, &path)?;
+
+	let rwlock = request.get::<State<CrawlerPoolShared>>().unwrap();
+	let crawler_pool = rwlock.read().unwrap();
+
+	match crawler_pool.add_source_to_crawl(source_id, path) {
+		Ok(false) => Err(ApiError::conflict("source is already being crawled").into()),
+		Ok(true) => {
+			let out_json = json!({
+				"status": "accepted",
+				"source_id": source_id
+			});
+			Ok(
+				Response::with(
+					(status::Accepted, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::internal(err).into())
+	}
+}
+
+/// Number of missing files listed when relocation fails
+const MISSING_FILES_SHOWN: usize = 20;
+
+/// Renames and/or relocates the source :id
+///
+/// Accepts `name` (empty string removes it) and `path`. Relocation keeps
+/// ids of photos, so tags, albums and renditions stay as they are. Every
+/// photo is checked at the new location first; if some are missing the
+/// source isn't changed unless `force` = true.
+pub fn update_source(request: &mut Request) -> IronResult<Response> {
+	let source_id = source_id(request);
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+	let name = param_string(&params, "name");
+	let path = param_string(&params, "path").filter(|path| !path.is_empty());
+	let force = param_string(&params, "force")
+		.map(|value| value == "true" || value == "1")
+		.unwrap_or(false);
+
+	let old_path: String = match connection.prep_exec(r"
+		SELECT `full_path` FROM `sources` WHERE `id` = :id",
+		params!{"id" => source_id}) {
+		Ok(mut result) => {
+			match result.next() {
+				Some(Ok(row)) => my::from_row(row),
+				_ => return Err(ApiError::not_found("source not found").into())
+			}
+		},
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	if let Some(ref path) = path {
+		let path = source_path(request, path)?;
+		let new_path = relocated_path(&old_path, &path);
+		let photos = match get_photos(&connection, source_id) {
+			Ok(photos) => photos,
+			Err(err) => return Err(ApiError::from(err).into())
+		};
+		let missing: Vec<String> = photos.values()
+			.filter_map(|full_path| full_path.get(old_path.len()..))
+			.filter(|relative_path| {
+				!decode_path(&format!("{}{}", new_path, relative_path)).is_file()
+			})
+			.map(|relative_path| relative_path.to_string())
+			.collect();
+
+		if !missing.is_empty() && !force {
+			let details = json!({
+				"missing": missing.len(),
+				"examples": missing.iter().take(MISSING_FILES_SHOWN).collect::<Vec<_>>()
+			});
+			return Err(
+				ApiError::conflict("photos are missing at the new path")
+					.with_details(details)
+					.into()
+			);
+		}
+
+		let result = connection.prep_exec(r"
+			UPDATE `sources` SET `full_path` = :path WHERE `id` = :id",
+			params!{"path" => &new_path, "id" => source_id});
+		if let Err(err) = result {
+			return Err(ApiError::from(err).into());
+		}
+	}
+
+	if let Some(name) = name {
+		let name = if name.trim().is_empty() { None } else { Some(name.trim().to_string()) };
+		let result = connection.prep_exec(r"
+			UPDATE `sources` SET `name` = :name WHERE `id` = :id",
+			params!{"name" => name, "id" => source_id});
+		if let Err(err) = result {
+			return Err(ApiError::from(err).into());
+		}
+	}
+
+	let out_json = json!({
+		"status": "ok"
+	});
+	Ok(
+		Response::with(
+			(status::Ok, to_string_pretty(&out_json).unwrap())
+		)
+	)
+}
+
+/// Enables the source :id hidden by disable_source
+pub fn enable_source(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+	set_source_enabled(&connection, source_id(request), true)
+}
+
+/// Temporarily hides the source :id and its photos, e.g. while its disk is
+/// unmounted. Tags, albums and renditions are kept.
+pub fn disable_source(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+	set_source_enabled(&connection, source_id(request), false)
+}
 
-	let mut source_id: u64 = 0;
+/// Removes the source :id with all its photos and their renditions
+///
+/// Original files are never touched.
+pub fn delete_source(request: &mut Request) -> IronResult<Response> {
+	let source_id = source_id(request);
+	let policy = {
+		let rwlock = request.get::<State<Settings>>().unwrap();
+		let settings = rwlock.read().unwrap();
+		PathPolicy::from_settings(&settings)
+	};
+	let connection = db::get_connection(request);
+
+	// Source goes to trash first, so it's purged along with its photos
+	let result = connection.prep_exec(r"
+		UPDATE `sources` SET `deleted_at` = IFNULL(`deleted_at`, NOW())
+		WHERE `id` = :id",
+		params!{"id" => source_id});
 	match result {
-		Ok(result) => {
-			source_id = result.last_insert_id();
+		Ok(ref result) if result.affected_rows() == 0 => {
+			return Err(ApiError::not_found("source not found").into());
 		},
-		Err(_) => ()
+		Ok(_) => {},
+		Err(err) => return Err(ApiError::from(err).into())
 	}
 
-	match crawl_source(String::from_value(path).unwrap(), &source_id){
+	match trash::purge_trashed(&connection, &policy, &[],
+		&[source_id.to_string()], false, false) {
+		Ok(summary) => {
+			let out_json = json!({
+				"status": "ok",
+				"photos": summary.photos
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+fn set_source_enabled(connection: &my::Pool, source_id: u64, enabled: bool)
+	-> IronResult<Response> {
+	let result = connection.prep_exec(r"
+		UPDATE `sources` SET `enabled` = :enabled WHERE `id` = :id",
+		params!{"enabled" => enabled, "id" => source_id});
+
+	match result {
+		// Rows keeping their value aren't counted as affected
+		Ok(ref result) if result.affected_rows() == 0 &&
+			!source_exists(connection, source_id) => {
+			Err(ApiError::not_found("source not found").into())
+		},
 		Ok(_) => {
-			// Source was successfully crawled
-			let _result = connection.prep_exec(r"
-			     UPDATE `sources` 
-			     SET   `status` = 'indexed' 
-			     WHERE `id` = :source_id", 
-			     params!{"source_id" => &source_id});
-			Ok(Response::with((status::Ok, "ok")))
+			let out_json = json!({
+				"status": "ok"
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
 		},
-		Err(err) => {Ok(Response::with((status::Ok, "Error: cannot crawl: {:?}", err)))}
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+fn source_exists(connection: &my::Pool, source_id: u64) -> bool {
+	connection.prep_exec(r"
+		SELECT `id` FROM `sources` WHERE `id` = :id",
+		params!{"id" => source_id})
+		.map(|mut result| result.next().is_some())
+		.unwrap_or(false)
+}
+
+/// Keeps trailing slash of the old source path, because relative paths of
+/// photos are appended to it as is
+fn relocated_path(old_path: &str, new_path: &str) -> String {
+	let new_path = new_path.trim_end_matches('/');
+	if old_path.ends_with('/') {
+		format!("{}/", new_path)
+	} else {
+		new_path.to_string()
 	}
+}
 
-	
+/// Resolves directory of a new or relocated source
+///
+/// Directory should be inside of one of `source_roots`, it's stored
+/// canonicalized and encoded with `encode_path`.
+fn source_path(request: &mut Request, path: &str) -> Result<String, ApiError> {
+	let policy = {
+		let rwlock = request.get::<State<Settings>>().unwrap();
+		let settings = rwlock.read().unwrap();
+		PathPolicy::from_settings(&settings)
+	};
+
+	Ok(encode_path(&policy.source(path)?))
+}
+
+/// Reads :id url segment
+fn source_id(request: &Request) -> u64 {
+	request.extensions.get::<Router>().unwrap()
+		.find("id").unwrap_or("0")
+		.parse::<u64>().unwrap_or(0)
 }
 
 /// Checks if file is jpeg-related
 ///
-/// It simply checks filename for the jp(e)g ending.
+/// It simply checks filename for the jp(e)g ending. The rest of the name
+/// doesn't have to be valid UTF-8.
 fn is_jpg(entry: &DirEntry) -> bool {
 
-	entry.file_name()
-		.to_str()
-		.map(|s| {
-			s.ends_with(".jpg") || 
-			s.ends_with(".jpeg") ||
-			s.ends_with(".JPG") ||
-			s.ends_with(".JPEG")
+	Path::new(entry.file_name())
+		.extension()
+		.map(|extension| {
+			let extension = extension.to_string_lossy().to_lowercase();
+			extension == "jpg" || extension == "jpeg"
 		})
 		.unwrap_or(false)
 }
 /// Extracts images from source
 /// Goes recursively over all files in specified path and adds found jpegs to database
-fn crawl_source(crawl_path: String, source_id: &u64) -> Result<bool, &'static str>{
-	
-
-	let source_path = crawl_path.clone();
-	let relative_paths = get_paths_of_images(crawl_path);
+fn crawl_source(connection: &my::Pool, crawl_path: String, source_id: &u64,
+	filter: &CrawlFilter, progress: &ProgressReporter)
+	-> Result<CrawlSummary, &'static str>{
+	progress.set_status("scanning");
+	let relative_paths = get_paths_of_images(crawl_path.clone(), filter, progress);
+	let (images, failed, linked) = identify_images(&crawl_path, &relative_paths);
+	progress.update(|progress| progress.failed += failed);
+
+	let mut summary = save_images_to_db(connection, images, source_id, progress)?;
+	summary.failed += failed;
+	summary.skipped += linked;
+	progress.update(|progress| progress.skipped = summary.skipped);
+	Ok(summary)
+}
 
+/// Reads size and identity of found files
+///
+/// Files are identified by device and inode, so a file reached through
+/// several links is taken once. Returns images along with numbers of
+/// unreadable files and files already taken via another link.
+fn identify_images(source_path: &str, relative_paths: &[String])
+	-> (Vec<GalleryImage>, u64, u64) {
 	let mut images: Vec<GalleryImage> = vec![];
+	let mut identities: HashSet<(u64, u64)> = HashSet::new();
+	let mut failed: u64 = 0;
+	let mut linked: u64 = 0;
 
 	for rel_path in relative_paths.iter(){
 		let full_path = format!("{}{}", source_path, rel_path);
-		let metadata = fs::metadata(&full_path).unwrap();
+		let metadata = match fs::metadata(decode_path(&full_path)) {
+			Ok(metadata) => metadata,
+			Err(err) => {
+				println!("Unable to read {}: {:?}", full_path, err);
+				failed += 1;
+				continue;
+			}
+		};
+
+		if !identities.insert((metadata.dev(), metadata.ino())) {
+			println!("Skipping {}, the file is already found via another link", full_path);
+			linked += 1;
+			continue;
+		}
 
 		images.push(
 			GalleryImage{
-				source_path: source_path.clone(),
+				source_path: source_path.to_string(),
 				relative_path: rel_path.clone(),
-				size: metadata.len()
+				size: metadata.len(),
+				device: metadata.dev(),
+				inode: metadata.ino()
 			}
 		)
-
-		//println!("{} - {} bytes", rel_path, metadata.len());
 	}
-	
-	save_images_to_db(images, source_id)
+	(images, failed, linked)
 }
 
 /// Adds images to database
 ///
-/// It saves only meta information about images to database
-fn save_images_to_db(images: Vec<GalleryImage>, source_id: &u64) -> Result<bool, &'static str> {
-	
+/// It saves only meta information about images to database. Images are
+/// inserted in batches of CRAWL_BATCH rows, each batch in a transaction.
+/// Images already known in the source are skipped, so crawling the same
+/// source again only adds new files. Images with device and inode of an
+/// already known photo, e.g. reached through a symlink shared between
+/// sources, are skipped as well. If a batch fails, its images are
+/// inserted one by one to find out which of them can't be saved.
+fn save_images_to_db(connection: &my::Pool, images: Vec<GalleryImage>, source_id: &u64,
+	progress: &ProgressReporter) -> Result<CrawlSummary, &'static str> {
+	progress.set_status("saving");
+
+	let mut summary = CrawlSummary::default();
+
+	for batch in images.chunks(CRAWL_BATCH) {
+		let inserted = match insert_images(connection, batch, *source_id) {
+			Ok(inserted) => inserted,
+			Err(err) => {
+				println!("Unable to save batch of images, saving one by one: {:?}", err);
+
+				let mut inserted: u64 = 0;
+				for image in batch.iter() {
+					match insert_images(connection, slice::from_ref(image), *source_id) {
+						Ok(count) => inserted += count,
+						Err(err) => {
+							println!("Unable to save {}: {:?}", image.relative_path, err);
+							summary.failed += 1;
+							progress.update(|progress| progress.failed += 1);
+						}
+					}
+				}
+				inserted
+			}
+		};
+
+		summary.inserted += inserted;
+		progress.update(|progress| progress.inserted += inserted);
+	}
+
+	summary.skipped = images.len() as u64 - summary.inserted - summary.failed;
+	progress.update(|progress| progress.skipped = summary.skipped);
+
+	Ok(summary)
+}
 
-	let connection = db::get_connection();
-	
+/// Inserts images with a single multi-row statement in a transaction
+///
+/// Returns number of inserted rows, images already in the source or known
+/// by their device and inode are ignored. Photos already in the source get
+/// device and inode of their file recorded.
+fn insert_images(connection: &my::Pool, images: &[GalleryImage], source_id: u64)
+	-> Result<u64, my::Error> {
+	let mut transaction = connection.start_transaction(false, None, None)?;
+	let images = without_linked(&mut transaction, images, source_id)?;
+	if images.is_empty() {
+		transaction.commit()?;
+		return Ok(0);
+	}
+
+	let mut values: Vec<my::Value> = vec![];
 	for image in images.iter() {
-		let result = connection.prep_exec(r"
-		     INSERT INTO `photos` 
-		             (`relative_path`, `source`, `filesize`) 
-		     VALUES  (:relative_path, :source, :filesize)", 
-			params!{
-				"relative_path" => image.relative_path.clone(),
-				"source" => source_id,
-				"filesize" => image.size
-			}
-		);
+		values.push(image.relative_path.clone().into());
+		values.push(source_id.into());
+		values.push(image.size.into());
+		values.push(image.device.into());
+		values.push(image.inode.into());
+	}
+	let placeholders = vec!["(?, ?, ?, ?, ?)"; images.len()].join(", ");
+
+	let existing = count_existing(&mut transaction, &images, source_id)?;
+	// Only duplicate keys are skipped, other row errors fail the batch
+	transaction.prep_exec(
+		format!("INSERT INTO `photos` (`relative_path`, `source`, `filesize`, \
+			`file_device`, `file_inode`) VALUES {} \
+			ON DUPLICATE KEY UPDATE `file_device` = VALUES(`file_device`), \
+			`file_inode` = VALUES(`file_inode`)", placeholders),
+		my::Params::Positional(values))?;
+	transaction.commit()?;
+
+	Ok((images.len() as u64).saturating_sub(existing))
+}
+
+/// Leaves out images which are already indexed at another path
+///
+/// Another photo with the same device and inode is a link to the same file
+/// only if its own path still leads to that file. Otherwise the file was
+/// deleted and its inode reused, so the stale identity is cleared.
+fn without_linked<'a>(transaction: &mut my::Transaction, images: &'a [GalleryImage],
+	source_id: u64) -> Result<Vec<&'a GalleryImage>, my::Error> {
+	if images.is_empty() {
+		return Ok(vec![]);
+	}
 
-		match result {
-			Ok(_) => (),
-			Err(err) => println!("{:?}", err)
+	let mut values: Vec<my::Value> = vec![];
+	for image in images.iter() {
+		values.push(image.device.into());
+		values.push(image.inode.into());
+	}
+	let conditions = vec!["(photos.file_device = ? AND photos.file_inode = ?)"; images.len()]
+		.join(" OR ");
+
+	let mut known: Vec<(u64, u64, String, String, u64, u64)> = vec![];
+	for row in transaction.prep_exec(
+		format!("SELECT photos.id, photos.source, photos.relative_path, \
+			CONCAT(`full_path`, `relative_path`), photos.file_device, photos.file_inode \
+			FROM `photos`, `sources` \
+			WHERE sources.id = photos.source AND ({})", conditions),
+		my::Params::Positional(values))? {
+		known.push(my::from_row(row?));
+	}
+
+	let mut linked: HashSet<(u64, u64)> = HashSet::new();
+	for (id, source, relative_path, full_path, device, inode) in known.into_iter() {
+		let same_photo = source == source_id && images.iter().any(|image| {
+			image.relative_path == relative_path &&
+				(image.device, image.inode) == (device, inode)
+		});
+		if same_photo {
+			continue;
 		}
 
+		let alive = fs::metadata(decode_path(&full_path))
+			.map(|metadata| metadata.dev() == device && metadata.ino() == inode)
+			.unwrap_or(false);
+		if alive {
+			println!("Skipping a link to photo {} at {}", id, full_path);
+			linked.insert((device, inode));
+
+			// The link may be indexed before identities were recorded, it's
+			// hidden as a duplicate then
+			for image in images.iter().filter(|image| (image.device, image.inode) == (device, inode)) {
+				transaction.prep_exec(r"
+					UPDATE `photos` SET `duplicate_of` = :id
+					WHERE `source` = :source
+					AND `relative_path_hash` = UNHEX(SHA2(:path, 256))
+					AND `id` <> :photo AND `duplicate_of` IS NULL",
+					params!{
+						"id" => id,
+						"source" => source_id,
+						"path" => &image.relative_path,
+						"photo" => id
+					})?;
+			}
+		} else {
+			transaction.prep_exec(r"
+				UPDATE `photos` SET `file_device` = NULL, `file_inode` = NULL
+				WHERE `id` = :id",
+				params!{"id" => id})?;
+		}
 	}
-	Ok(true)
+
+	Ok(images.iter()
+		.filter(|image| !linked.contains(&(image.device, image.inode)))
+		.collect())
 }
 
-/// Extacts relative paths of images in specified directory recursively.
-fn get_paths_of_images(search_path: String) -> Vec<String> {
+/// Counts images already stored in the source, affected rows of upsert
+/// can't tell inserted rows from skipped ones
+fn count_existing(transaction: &mut my::Transaction, images: &[&GalleryImage],
+	source_id: u64) -> Result<u64, my::Error> {
+	let mut values: Vec<my::Value> = vec![source_id.into()];
+	for image in images.iter() {
+		values.push(image.relative_path.clone().into());
+	}
+	let placeholders = vec!["UNHEX(SHA2(?, 256))"; images.len()].join(", ");
+
+	let mut count: u64 = 0;
+	for row in transaction.prep_exec(
+		format!("SELECT COUNT(*) FROM `photos` \
+			WHERE `source` = ? AND `relative_path_hash` IN ({})", placeholders),
+		my::Params::Positional(values))? {
+		count = my::from_row(row?);
+	}
+	Ok(count)
+}
 
-	let walker = WalkDir::new(search_path.clone()).into_iter();
+/// Extacts relative paths of images in specified directory recursively.
+///
+/// `search_path` and returned paths are in the stored form, see
+/// `encode_path`. Relative paths are appended to the source path as is,
+/// so they start with "/" unless the source path ends with it.
+///
+/// Ignored directories aren't entered, ignored and too small images are
+/// counted in `ignored` progress.
+fn get_paths_of_images(search_path: String, filter: &CrawlFilter,
+	progress: &ProgressReporter) -> Vec<String> {
+
+	let root = decode_path(&search_path);
+	let separator = if search_path.ends_with('/') { "" } else { "/" };
+
+	let mut ignore_stack = filter.walk(&root);
+	let walker = filter.walk_dir(&root).into_iter()
+		.filter_entry(|entry| {
+			let keep = ignore_stack.keep(entry);
+			if !keep && is_jpg(entry) {
+				progress.update(|progress| progress.ignored += 1);
+			}
+			keep
+		});
 
 	let mut paths: Vec<String>= vec![];
 
-	for entry in walker.filter_map(|e| {
-		if is_jpg(e.as_ref().unwrap()) {
-			Some(e)
-		} else {
+	for entry in walker.filter_map(|e| match e {
+		Ok(entry) => if is_jpg(&entry) { Some(entry) } else { None },
+		Err(ref err) if err.loop_ancestor().is_some() => {
+			println!("Skipping symlink loop at {:?}", err.path());
+			None
+		},
+		Err(err) => {
+			println!("Unable to read {:?}", err);
 			None
 		}
 	}) {
-		let crawl_path_len = search_path.chars().count();
-		let entry = entry.unwrap();
-		let full_path = match entry.path().to_str(){ 
-			Some(path) => path.to_string(),
-			None       => "".to_string()
-		};
-		
-		//paths.push(full_path.clone());
-
-		let relative_path: String = full_path
-			.chars()
-			.skip(crawl_path_len)
-			.collect();
+		let size = entry.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+		if !filter.accepts_file(entry.path(), size) {
+			progress.update(|progress| progress.ignored += 1);
+			continue;
+		}
 
-		paths.push(relative_path);
+		let relative_path = match entry.path().strip_prefix(&root) {
+			Ok(relative_path) => relative_path,
+			Err(_) => {
+				println!("Skipping {:?} outside of {:?}", entry.path(), root);
+				continue;
+			}
+		};
 
-		//println!("{:?}", paths);
+		paths.push(format!("{}{}", separator, encode_path(relative_path)));
+		progress.update(|progress| progress.found += 1);
 	}
 	paths
 }
 
 
-pub fn get_photos(source_id: u64) -> HashMap<u64, String> {
-	let connection = db::get_connection();
-	
+pub fn get_photos(connection: &my::Pool, source_id: u64)
+	-> Result<HashMap<u64, String>, my::Error> {
 	// Select all photos from this source_id
 	let result = connection.prep_exec(r"
 		SELECT photos.id as id, CONCAT(`full_path`,`relative_path`) as 
@@ -247,8 +809,8 @@ pub fn get_photos(source_id: u64) -> HashMap<u64, String> {
 		WHERE sources.id=photos.source AND
 		sources.id=:source_id",
 		params!{"source_id" => source_id}
-	).unwrap();
-		
+	)?;
+
 	// We'll store images as pair id - absolute path
 	let mut images: HashMap<u64, String> = HashMap::new();
 
@@ -263,5 +825,131 @@ pub fn get_photos(source_id: u64) -> HashMap<u64, String> {
 		}
 	});
 	println!("images list size: {:?}", images.len());
-	return images;
+	Ok(images)
+}
+
+/// Returns absolute path of the original file of the photo
+pub fn get_photo_path(connection: &my::Pool, photo_id: u64) -> Option<String> {
+
+	let result = connection.prep_exec(r"
+		SELECT CONCAT(`full_path`,`relative_path`) FROM `photos`, `sources`
+		WHERE sources.id=photos.source AND
+		photos.id=:photo_id",
+		params!{"photo_id" => photo_id}
+	);
+
+	match result {
+		Ok(mut result) => {
+			match result.next() {
+				Some(Ok(row)) => Some(my::from_row(row)),
+				_ => None
+			}
+		},
+		Err(_) => None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::ffi::OsStr;
+	use std::fs;
+	use std::fs::File;
+	use std::os::unix::ffi::OsStrExt;
+	use std::os::unix::fs::symlink;
+	use std::path::PathBuf;
+
+	use super::{get_paths_of_images, identify_images};
+	use crawl_filter::CrawlFilter;
+	use crawl_filter::tests::tree;
+	use crawler_pool::ProgressReporter;
+	use paths::{encode_path, decode_path};
+
+	/// Creates a source with multibyte root name and awkward file names
+	fn source_tree(name: &str) -> PathBuf {
+		let root = tree(name, &[
+			"корень/Фото/отпуск.jpg",
+			"корень/🎉 party/😀.JPG",
+			"корень/100%.jpg",
+			"корень/notes.txt"
+		]).join("корень");
+		File::create(root.join(OsStr::from_bytes(b"bad\xFFname.jpg"))).unwrap();
+		root
+	}
+
+	fn crawl(search_path: &str) -> Vec<String> {
+		crawl_with(search_path, &HashMap::new())
+	}
+
+	fn crawl_with(search_path: &str, settings: &HashMap<String, String>) -> Vec<String> {
+		let filter = CrawlFilter::from_settings(settings);
+		let mut paths = get_paths_of_images(search_path.to_string(), &filter,
+			&ProgressReporter::detached());
+		paths.sort();
+		paths
+	}
+
+	#[test]
+	fn relative_paths_are_stored_losslessly() {
+		let root = source_tree("lossless");
+		let paths = crawl(&encode_path(&root));
+
+		assert_eq!(paths, vec![
+			"/100%25.jpg".to_string(),
+			"/bad%FFname.jpg".to_string(),
+			"/Фото/отпуск.jpg".to_string(),
+			"/🎉 party/😀.JPG".to_string()
+		]);
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn stored_paths_lead_back_to_files() {
+		let root = source_tree("roundtrip");
+		let source = encode_path(&root);
+
+		for relative_path in crawl(&source).iter() {
+			let path = decode_path(&format!("{}{}", source, relative_path));
+			assert!(path.is_file(), "{:?} doesn't exist", path);
+		}
+		assert!(root.join(OsStr::from_bytes(b"bad\xFFname.jpg")).is_file());
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn trailing_slash_of_source_is_respected() {
+		let root = source_tree("trailing_slash");
+		let source = format!("{}/", encode_path(&root));
+		let paths = crawl(&source);
+
+		assert_eq!(paths.len(), 4);
+		for relative_path in paths.iter() {
+			assert!(!relative_path.starts_with('/'));
+			assert!(decode_path(&format!("{}{}", source, relative_path)).is_file());
+		}
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn file_reached_via_several_links_is_taken_once() {
+		let root = tree("identity", &["a.jpg", "sub/b.jpg"]);
+		symlink(root.join("sub"), root.join("link")).unwrap();
+		fs::hard_link(root.join("a.jpg"), root.join("copy.jpg")).unwrap();
+		let mut settings = HashMap::new();
+		settings.insert("crawl_follow_symlinks".to_string(), "true".to_string());
+		settings.insert("source_roots".to_string(), root.to_string_lossy().into_owned());
+
+		let source = encode_path(&root);
+		let paths = crawl_with(&source, &settings);
+		assert_eq!(paths, vec!["/a.jpg", "/copy.jpg", "/link/b.jpg", "/sub/b.jpg"]);
+
+		let (images, failed, linked) = identify_images(&source, &paths);
+		let mut taken = images.iter()
+			.map(|image| image.relative_path.as_str())
+			.collect::<Vec<&str>>();
+		taken.sort();
+		assert_eq!(taken, vec!["/a.jpg", "/link/b.jpg"]);
+		assert_eq!((failed, linked), (0, 2));
+		fs::remove_dir_all(&root).unwrap();
+	}
 }
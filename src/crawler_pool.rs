@@ -0,0 +1,209 @@
+// Standard library includes
+use std::sync::Mutex;
+use std::sync::Arc;
+use std::thread;
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+
+// Library includes
+use iron::typemap::Key;
+use mysql as my;
+
+// Local includes
+use crawler;
+use crawler::CrawlOptions;
+
+/// How long progress of a finished crawl stays available
+const FINISHED_PROGRESS_KEPT: u64 = 600;
+
+/// Runs crawling of new sources in a background thread
+///
+/// Sources are crawled one by one in order they were added. Progress of
+/// every source is kept in memory and available through status_of, which
+/// is reported by /api/process_status. Progress of finished crawls is
+/// dropped after FINISHED_PROGRESS_KEPT seconds.
+#[derive(Debug)]
+pub struct CrawlerPool {
+	/// Handlers share the pool, so sending is serialized
+	job_sender: Mutex<mpsc::Sender<CrawlJob>>,
+	progress: ProgressMap
+}
+
+#[derive(Debug)]
+struct CrawlJob {
+	source_id: u64,
+	path: String
+}
+
+/// Progress of crawling a single source
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CrawlProgress {
+	/// queued, scanning, saving, importing, done or failed
+	pub status: String,
+	/// Number of images found in the source so far
+	pub found: u64,
+	/// Number of images saved to DB so far
+	pub inserted: u64,
+	/// Number of images which were already in DB
+	pub skipped: u64,
+	/// Number of files which couldn't be read or saved
+	pub failed: u64,
+	/// Number of images skipped by ignore rules or size thresholds
+	pub ignored: u64,
+	pub error: Option<String>,
+	#[serde(skip)]
+	finished_at: Option<Instant>
+}
+
+impl CrawlProgress {
+	/// Whether the crawl is done or failed
+	pub fn is_finished(&self) -> bool {
+		self.finished_at.is_some()
+	}
+}
+
+type ProgressMap = Arc<Mutex<HashMap<u64, CrawlProgress>>>;
+
+/// Handle used by crawler to report progress of a single source
+#[derive(Debug, Clone)]
+pub struct ProgressReporter {
+	source_id: u64,
+	progress: ProgressMap
+}
+
+impl ProgressReporter {
+	/// Reporter which isn't attached to any pool, progress is discarded
+	#[cfg(test)]
+	pub fn detached() -> ProgressReporter {
+		ProgressReporter {
+			source_id: 0,
+			progress: Arc::new(Mutex::new(HashMap::new()))
+		}
+	}
+
+	/// Changes progress of the source
+	pub fn update<F: FnOnce(&mut CrawlProgress)>(&self, change: F) {
+		// Progress stays usable after a panic in the crawler
+		let mut progress_map = self.progress.lock().unwrap_or_else(|err| err.into_inner());
+		if let Some(progress) = progress_map.get_mut(&self.source_id) {
+			change(progress);
+		}
+	}
+
+	pub fn set_status(&self, status: &str) {
+		self.update(|progress| progress.status = status.to_string());
+	}
+
+	/// Marks the crawl as done, or failed with `error`
+	fn finish(&self, error: Option<&str>) {
+		self.update(|progress| {
+			progress.status = if error.is_some() { "failed" } else { "done" }.to_string();
+			progress.error = error.map(|error| error.to_string());
+			progress.finished_at = Some(Instant::now());
+		});
+	}
+}
+
+impl CrawlerPool {
+	/// Create a new CrawlerPool with only one working thread
+	pub fn new(settings: HashMap<String, String>, pool: my::Pool) -> CrawlerPool {
+		let (job_sender, job_receiver) = mpsc::channel::<CrawlJob>();
+		let progress: ProgressMap = Arc::new(Mutex::new(HashMap::new()));
+
+		let thread_progress = progress.clone();
+		thread::spawn(move || {
+			let options = CrawlOptions::from_settings(&settings);
+
+			// Waiting for job from the receiving end of the channel, the
+			// loop ends when the pool is dropped
+			while let Ok(job) = job_receiver.recv() {
+				println!("CrawlerPool got a job; Crawling source_id: {} at {}",
+					job.source_id, job.path);
+
+				let reporter = ProgressReporter {
+					source_id: job.source_id,
+					progress: thread_progress.clone()
+				};
+
+				// A panic fails the job, the thread keeps serving next ones
+				let result = panic::catch_unwind(AssertUnwindSafe(|| {
+					crawler::index_source(&pool, job.source_id, &job.path,
+						&options, &reporter)
+				})).unwrap_or(Err("Crawler panicked"));
+
+				match result {
+					Ok(summary) => {
+						println!("Crawled source_id: {}: {:?}", job.source_id, summary);
+						reporter.finish(None);
+					},
+					Err(err) => {
+						println!("Unable to crawl source_id: {}: {}", job.source_id, err);
+						reporter.finish(Some(err));
+					}
+				}
+
+				println!("Crawl Done! source_id: {:?}", job.source_id);
+			}
+		});
+
+		CrawlerPool {
+			job_sender: Mutex::new(job_sender),
+			progress: progress
+		}
+	}
+
+	/// Queues crawling of the source
+	///
+	/// Returns false without queuing if the source is already queued or
+	/// being crawled.
+	pub fn add_source_to_crawl(&self, source_id: u64, path: String)
+		-> Result<bool, &'static str> {
+		{
+			let mut progress = self.progress.lock().unwrap_or_else(|err| err.into_inner());
+			CrawlerPool::evict_finished(&mut progress);
+			if progress.get(&source_id).map(|progress| !progress.is_finished()).unwrap_or(false) {
+				return Ok(false);
+			}
+			progress.insert(source_id, CrawlProgress {
+				status: "queued".to_string(),
+				found: 0,
+				inserted: 0,
+				skipped: 0,
+				failed: 0,
+				ignored: 0,
+				error: None,
+				finished_at: None
+			});
+		}
+
+		let job = CrawlJob { source_id: source_id, path: path };
+		let job_sender = self.job_sender.lock().unwrap_or_else(|err| err.into_inner());
+		match job_sender.send(job) {
+			Ok(_) => Ok(true),
+			Err(_) => Err("Cannot send job to CrawlerPool")
+		}
+	}
+
+	/// Returns progress of crawling the source, None if it isn't crawled
+	/// and didn't finish recently
+	pub fn status_of(&self, source_id: u64) -> Option<CrawlProgress> {
+		let mut progress = self.progress.lock().unwrap_or_else(|err| err.into_inner());
+		CrawlerPool::evict_finished(&mut progress);
+		progress.get(&source_id).cloned()
+	}
+
+	/// Drops progress of crawls finished more than FINISHED_PROGRESS_KEPT
+	/// seconds ago
+	fn evict_finished(progress: &mut HashMap<u64, CrawlProgress>) {
+		let kept = Duration::from_secs(FINISHED_PROGRESS_KEPT);
+		progress.retain(|_, progress| {
+			progress.finished_at.map(|finished_at| finished_at.elapsed() < kept).unwrap_or(true)
+		});
+	}
+}
+
+pub struct CrawlerPoolShared;
+impl Key for CrawlerPoolShared { type Value = CrawlerPool; }
@@ -1,21 +1,78 @@
 // Standard library includes
+use std::collections::HashMap;
 use std::env;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
 // Library includes
+use iron::prelude::*;
+use iron::typemap::Key;
+use persistent::State;
 use mysql as my;
 
-fn get_opts() -> my::Opts {
+/// Key of the connection pool shared by all handlers
+pub struct DbPool;
+impl Key for DbPool { type Value = my::Pool; }
+
+fn get_opts(settings: &HashMap<String, String>) -> my::Opts {
+	let timeout = |name: &str| {
+		setting::<u64>(settings, name).map(Duration::from_secs)
+	};
+
 	let mut builder = my::OptsBuilder::new();
 	builder
 	    .ip_or_hostname(env::var("DB_HOST").ok())
 	    .db_name(env::var("DB_DATABASE").ok())
 	    .user(env::var("DB_USER").ok())
-	    .pass(env::var("DB_PASS").ok());
-	
+	    .pass(env::var("DB_PASS").ok())
+	    .tcp_connect_timeout(timeout("db_connect_timeout"))
+	    .read_timeout(timeout("db_read_timeout"))
+	    .write_timeout(timeout("db_write_timeout"));
+
 	builder.into()
 }
 
-pub fn get_connection() -> my::Pool {
-	let options = get_opts();
-	my::Pool::new(options).unwrap()
-}
\ No newline at end of file
+/// Creates connection pool shared by the whole application
+///
+/// Pool size is configured by `db_pool_min` and `db_pool_max` settings,
+/// timeouts in seconds by `db_connect_timeout`, `db_read_timeout` and
+/// `db_write_timeout`. If DB is unreachable, connecting is retried
+/// `db_connect_retries` times every `db_connect_retry_delay` seconds, so
+/// the server may start before the DB does.
+pub fn create_pool(settings: &HashMap<String, String>) -> Result<my::Pool, my::Error> {
+	let min = setting(settings, "db_pool_min").unwrap_or(1);
+	let max = setting(settings, "db_pool_max").unwrap_or(10).max(min).max(1);
+	let retries = setting::<u32>(settings, "db_connect_retries").unwrap_or(5);
+	let delay = Duration::from_secs(
+		setting(settings, "db_connect_retry_delay").unwrap_or(3));
+
+	let mut attempt = 0;
+	loop {
+		match my::Pool::new_manual(min, max, get_opts(settings)) {
+			Ok(pool) => return Ok(pool),
+			Err(err) => {
+				if attempt >= retries {
+					return Err(err);
+				}
+				attempt += 1;
+				println!("Unable to connect to DB: {}. Retrying in {:?} ({}/{})",
+					err, delay, attempt, retries);
+				thread::sleep(delay);
+			}
+		}
+	}
+}
+
+/// Provides the shared connection pool to a request handler
+///
+/// Pool is cheap to clone, all clones share the same connections.
+pub fn get_connection(request: &mut Request) -> my::Pool {
+	let rwlock = request.get::<State<DbPool>>().unwrap();
+	let pool = rwlock.read().unwrap();
+	pool.clone()
+}
+
+fn setting<T: FromStr>(settings: &HashMap<String, String>, name: &str) -> Option<T> {
+	settings.get(name).and_then(|value| T::from_str(value).ok())
+}
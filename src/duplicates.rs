@@ -0,0 +1,174 @@
+// Standard library includes
+use std::str::FromStr;
+
+// Library includes
+use router::Router;
+use iron::prelude::*;
+use iron::status;
+use params::FromValue;
+use mysql as my;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+use search::visible;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DuplicatePhoto {
+	id: u64,
+	source: u64,
+	full_path: String,
+	filesize: u64
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DuplicateGroup {
+	hash: String,
+	canonical: Option<u64>,
+	photos: Vec<DuplicatePhoto>
+}
+
+/// Provides groups of byte-identical photos across all sources
+///
+/// Only hashes shared by two or more photos are reported. `canonical` is
+/// the photo chosen to stay visible, or null if none was chosen yet.
+pub fn list_duplicates(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+	let result = connection.prep_exec(format!(r"
+		SELECT photos.content_hash, photos.id, photos.source,
+		CONCAT(`full_path`,`relative_path`), photos.filesize,
+		photos.duplicate_of
+		FROM `photos`, `sources`
+		WHERE sources.id = photos.source AND {} AND photos.content_hash IN (
+			SELECT `content_hash` FROM `photos`
+			WHERE `content_hash` IS NOT NULL
+			GROUP BY `content_hash` HAVING COUNT(*) > 1
+		)
+		ORDER BY photos.content_hash, photos.id", visible("photos")), ());
+
+	let result = match result {
+		Ok(result) => result,
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	let mut groups: Vec<DuplicateGroup> = vec![];
+
+	result.for_each(|row| {
+		match row {
+			Ok(row) => {
+				let (hash, id, source, full_path, filesize, duplicate_of):
+					(String, u64, u64, String, u64, Option<u64>) =
+					my::from_row(row);
+
+				let is_new_group = match groups.last() {
+					Some(group) => group.hash != hash,
+					None => true
+				};
+				if is_new_group {
+					groups.push(DuplicateGroup {
+						hash: hash,
+						canonical: None,
+						photos: vec![]
+					});
+				}
+
+				let group = groups.last_mut().unwrap();
+				if duplicate_of.is_some() {
+					group.canonical = duplicate_of;
+				}
+				group.photos.push(DuplicatePhoto {
+					id: id,
+					source: source,
+					full_path: full_path,
+					filesize: filesize
+				});
+			},
+			Err(_) => {}
+		}
+	});
+
+	let out_json = json!({
+		"duplicates": groups,
+	});
+
+	Ok(
+		Response::with(
+			(status::Ok, to_string_pretty(&out_json).unwrap())
+		)
+	)
+}
+
+/// Marks canonical copy within a group of duplicates
+///
+/// All other photos with the same content hash become hidden from listings.
+/// Passing `photo_id` = 0 clears the choice and shows all copies again.
+pub fn set_canonical(request: &mut Request) -> IronResult<Response> {
+	let hash = request.extensions.get::<Router>().unwrap()
+		.find("hash").unwrap_or("").to_string();
+
+	let params = request_params(request)?;
+	let photo_id: u64 = match params.find(&["photo_id"]) {
+		Some(value) => {
+			u64::from_str(
+				String::from_value(value)
+				.unwrap_or(String::new())
+				.as_str()
+			).unwrap_or(0)
+		},
+		None => 0
+	};
+
+	let connection = db::get_connection(request);
+
+	if photo_id != 0 {
+		// Canonical photo should belong to the group
+		let result = connection.prep_exec(r"
+			SELECT COUNT(*) FROM `photos`
+			WHERE `id` = :id AND `content_hash` = :hash",
+			params!{"id" => photo_id, "hash" => &hash}
+		);
+
+		let count: u64 = match result {
+			Ok(mut result) => {
+				match result.next() {
+					Some(Ok(row)) => my::from_row(row),
+					_ => 0
+				}
+			},
+			Err(err) => return Err(ApiError::from(err).into())
+		};
+
+		if count == 0 {
+			return Err(ApiError::not_found("photo with such hash not found").into());
+		}
+	}
+
+	let result = connection.prep_exec(r"
+		UPDATE `photos`
+		SET   `duplicate_of` = IF(:photo_id = 0 OR `id` = :canonical_id,
+		                          NULL, :duplicate_of)
+		WHERE `content_hash` = :hash",
+		params!{
+			"photo_id" => photo_id,
+			"canonical_id" => photo_id,
+			"duplicate_of" => photo_id,
+			"hash" => &hash
+		}
+	);
+
+	match result {
+		Ok(result) => {
+			let out_json = json!({
+				"status": "ok",
+				"updated": result.affected_rows()
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
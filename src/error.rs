@@ -0,0 +1,129 @@
+// Standard library includes
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// Library includes
+use iron::prelude::*;
+use iron::status;
+use iron::status::Status;
+use iron::AfterMiddleware;
+use router::NoRoute;
+use params::{Params, Map};
+use mysql as my;
+use serde_json;
+use serde_json::to_string_pretty;
+
+/// Error returned by API handlers
+///
+/// Turns into a response with matching HTTP status and JSON body
+/// `{code, message, details}`. `code` is a stable machine-readable name,
+/// `message` is meant for people and `details` is optional extra data,
+/// e.g. the list of missing files.
+#[derive(Debug)]
+pub struct ApiError {
+	pub status: Status,
+	pub code: &'static str,
+	pub message: String,
+	pub details: Option<serde_json::Value>
+}
+
+impl ApiError {
+	pub fn new(status: Status, code: &'static str, message: &str) -> ApiError {
+		ApiError {
+			status: status,
+			code: code,
+			message: message.to_string(),
+			details: None
+		}
+	}
+
+	pub fn bad_request(message: &str) -> ApiError {
+		ApiError::new(status::BadRequest, "bad_request", message)
+	}
+
+	pub fn not_found(message: &str) -> ApiError {
+		ApiError::new(status::NotFound, "not_found", message)
+	}
+
+	pub fn forbidden(message: &str) -> ApiError {
+		ApiError::new(status::Forbidden, "forbidden", message)
+	}
+
+	pub fn conflict(message: &str) -> ApiError {
+		ApiError::new(status::Conflict, "conflict", message)
+	}
+
+	pub fn internal(message: &str) -> ApiError {
+		ApiError::new(status::InternalServerError, "internal", message)
+	}
+
+	/// Attaches extra data to the error
+	pub fn with_details(mut self, details: serde_json::Value) -> ApiError {
+		self.details = Some(details);
+		self
+	}
+
+	fn body(&self) -> String {
+		let out_json = json!({
+			"code": self.code,
+			"message": self.message,
+			"details": self.details,
+		});
+		to_string_pretty(&out_json).unwrap()
+	}
+}
+
+impl fmt::Display for ApiError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}: {}", self.code, self.message)
+	}
+}
+
+impl Error for ApiError {
+	fn description(&self) -> &str {
+		&self.message
+	}
+}
+
+/// DB errors are logged, their text isn't sent to the client
+impl From<my::Error> for ApiError {
+	fn from(err: my::Error) -> ApiError {
+		println!("{:?}", err);
+		ApiError::new(status::InternalServerError, "database", "Database error")
+	}
+}
+
+impl From<io::Error> for ApiError {
+	fn from(err: io::Error) -> ApiError {
+		println!("{:?}", err);
+		ApiError::new(status::InternalServerError, "io", "Unable to access file")
+	}
+}
+
+impl From<ApiError> for IronError {
+	fn from(err: ApiError) -> IronError {
+		let response = (err.status, err.body());
+		IronError::new(err, response)
+	}
+}
+
+/// Reads request parameters, malformed body is a bad request
+pub fn request_params(request: &mut Request) -> Result<Map, ApiError> {
+	request.get::<Params>().map_err(|err| {
+		ApiError::bad_request("Malformed request parameters")
+			.with_details(json!(format!("{:?}", err)))
+	})
+}
+
+/// Gives unknown routes the same JSON error body as handlers
+pub struct JsonErrors;
+
+impl AfterMiddleware for JsonErrors {
+	fn catch(&self, _: &mut Request, err: IronError) -> IronResult<Response> {
+		if err.error.is::<NoRoute>() {
+			return Err(ApiError::not_found("Route not found").into());
+		}
+		Err(err)
+	}
+}
@@ -0,0 +1,358 @@
+// Standard library includes
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+
+// Library includes
+use router::Router;
+use iron::prelude::*;
+use iron::status;
+use persistent::State;
+use mysql as my;
+use exif::{Reader, Value, Tag};
+use regex::Regex;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::ApiError;
+use tags;
+use paths::PathPolicy;
+use Settings;
+
+/// Comparison used in rule condition
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+	Equal,
+	NotEqual,
+	Greater,
+	GreaterOrEqual,
+	Less,
+	LessOrEqual
+}
+
+/// Single rule: tag template applied when condition holds
+#[derive(Debug)]
+struct Rule {
+	/// None means the rule is always applied
+	condition: Option<(String, Operator, String)>,
+	template: String
+}
+
+/// Rules generating tags from EXIF attributes
+///
+/// Configured in `exif_tag_rules` setting, one rule per line:
+///
+/// ```text
+/// always => camera/{camera}
+/// orientation = portrait => portrait
+/// exposure_time >= 1 => night
+/// ```
+///
+/// Condition is either `always` or `attribute operator value` where
+/// operator is one of `=`, `!=`, `>`, `>=`, `<`, `<=`. Template may
+/// reference attributes as `{name}`; the tag is skipped if any of them is
+/// unknown for the photo. Available attributes: camera, lens, year, width,
+/// height, orientation (portrait, landscape or square), exposure_time
+/// (seconds), flash (true or false), iso, focal_length (mm).
+#[derive(Debug)]
+pub struct ExifTagRules {
+	rules: Vec<Rule>,
+	/// Matches `{name}` attribute references in templates
+	placeholder: Regex
+}
+
+impl ExifTagRules {
+	/// Reads rules from settings. Malformed rules are reported and skipped.
+	pub fn from_settings(settings: &HashMap<String, String>) -> ExifTagRules {
+		let condition_regex =
+			Regex::new(r#"^(\w+)\s*(!=|>=|<=|=|>|<)\s*"?([^"]*)"?$"#).unwrap();
+		let mut rules: Vec<Rule> = vec![];
+
+		for line in settings.get("exif_tag_rules")
+			.map(|value| value.as_str())
+			.unwrap_or("")
+			.lines() {
+			let line = line.trim();
+			if line.is_empty() {
+				continue;
+			}
+
+			let mut parts = line.splitn(2, "=>");
+			let condition = parts.next().unwrap_or("").trim();
+			let template = parts.next().unwrap_or("").trim().to_string();
+
+			if template.is_empty() {
+				println!("Invalid EXIF tag rule {:?}: no tag", line);
+				continue;
+			}
+
+			if condition == "always" {
+				rules.push(Rule { condition: None, template: template });
+				continue;
+			}
+
+			match condition_regex.captures(condition) {
+				Some(captures) => {
+					let operator = match &captures[2] {
+						"=" => Operator::Equal,
+						"!=" => Operator::NotEqual,
+						">" => Operator::Greater,
+						">=" => Operator::GreaterOrEqual,
+						"<" => Operator::Less,
+						_ => Operator::LessOrEqual
+					};
+					rules.push(Rule {
+						condition: Some((
+							captures[1].to_string(),
+							operator,
+							captures[3].to_string()
+						)),
+						template: template
+					});
+				},
+				None => println!("Invalid EXIF tag rule {:?}: bad condition", line)
+			}
+		}
+
+		ExifTagRules {
+			rules: rules,
+			placeholder: Regex::new(r"\{(\w+)\}").unwrap()
+		}
+	}
+
+	/// Produces tags for a photo with given attributes
+	fn tags_for(&self, attributes: &HashMap<&'static str, String>) -> Vec<String> {
+		let mut result: Vec<String> = vec![];
+
+		for rule in self.rules.iter() {
+			if let Some((ref name, operator, ref expected)) = rule.condition {
+				let actual = match attributes.get(name.as_str()) {
+					Some(actual) => actual,
+					None => continue
+				};
+				if !compare(actual, operator, expected) {
+					continue;
+				}
+			}
+
+			let mut complete = true;
+			let tag = self.placeholder.replace_all(&rule.template, |captures: &::regex::Captures| {
+				match attributes.get(&captures[1]) {
+					Some(value) if !value.is_empty() => value.clone(),
+					_ => {
+						complete = false;
+						String::new()
+					}
+				}
+			}).trim().to_string();
+
+			if complete && !tag.is_empty() && !result.contains(&tag) {
+				result.push(tag);
+			}
+		}
+
+		result
+	}
+}
+
+/// Compares attribute value with the value from rule
+///
+/// Numbers are compared numerically, everything else as case-insensitive
+/// strings (ordering operators never match strings).
+fn compare(actual: &str, operator: Operator, expected: &str) -> bool {
+	match (actual.parse::<f64>(), expected.parse::<f64>()) {
+		(Ok(actual), Ok(expected)) => {
+			match operator {
+				Operator::Equal => actual == expected,
+				Operator::NotEqual => actual != expected,
+				Operator::Greater => actual > expected,
+				Operator::GreaterOrEqual => actual >= expected,
+				Operator::Less => actual < expected,
+				Operator::LessOrEqual => actual <= expected
+			}
+		},
+		_ => {
+			let equal = actual.to_lowercase() == expected.to_lowercase();
+			match operator {
+				Operator::Equal => equal,
+				Operator::NotEqual => !equal,
+				_ => false
+			}
+		}
+	}
+}
+
+/// Reads attributes used by rules from EXIF
+///
+/// Camera and year are passed by the caller which has them already parsed.
+fn read_attributes(reader: &Reader, camera: Option<String>, year: Option<u32>)
+	-> HashMap<&'static str, String> {
+	let mut attributes: HashMap<&'static str, String> = HashMap::new();
+
+	if let Some(camera) = camera {
+		attributes.insert("camera", camera);
+	}
+	if let Some(year) = year {
+		attributes.insert("year", year.to_string());
+	}
+	if let Some(lens) = read_ascii(reader, Tag::LensModel) {
+		attributes.insert("lens", lens);
+	}
+	if let Some(exposure_time) = read_f64(reader, Tag::ExposureTime) {
+		attributes.insert("exposure_time", exposure_time.to_string());
+	}
+	if let Some(focal_length) = read_f64(reader, Tag::FocalLength) {
+		attributes.insert("focal_length", focal_length.to_string());
+	}
+	if let Some(iso) = read_uint(reader, Tag::PhotographicSensitivity) {
+		attributes.insert("iso", iso.to_string());
+	}
+	if let Some(flash) = read_uint(reader, Tag::Flash) {
+		// Bit 0 tells whether flash fired
+		attributes.insert("flash", (flash & 1 == 1).to_string());
+	}
+
+	let width = read_uint(reader, Tag::PixelXDimension);
+	let height = read_uint(reader, Tag::PixelYDimension);
+	if let (Some(width), Some(height)) = (width, height) {
+		// Orientations 5-8 rotate the picture by 90 degrees
+		let rotated = match read_uint(reader, Tag::Orientation) {
+			Some(5) | Some(6) | Some(7) | Some(8) => true,
+			_ => false
+		};
+		let (width, height) = if rotated { (height, width) } else { (width, height) };
+
+		attributes.insert("width", width.to_string());
+		attributes.insert("height", height.to_string());
+		attributes.insert("orientation", if width > height {
+			"landscape"
+		} else if width < height {
+			"portrait"
+		} else {
+			"square"
+		}.to_string());
+	}
+
+	attributes
+}
+
+fn read_ascii(reader: &Reader, tag: Tag) -> Option<String> {
+	match reader.get_field(tag, false) {
+		Some(field) => {
+			match field.value {
+				Value::Ascii(ref vec) if !vec.is_empty() => {
+					let value = String::from_utf8_lossy(vec[0])
+						.trim_matches(char::from(0))
+						.trim()
+						.to_string();
+					if value.is_empty() { None } else { Some(value) }
+				},
+				_ => None
+			}
+		},
+		None => None
+	}
+}
+
+fn read_uint(reader: &Reader, tag: Tag) -> Option<u32> {
+	match reader.get_field(tag, false) {
+		Some(field) => {
+			match field.value {
+				Value::Short(ref vec) if !vec.is_empty() => Some(vec[0] as u32),
+				Value::Long(ref vec) if !vec.is_empty() => Some(vec[0]),
+				_ => None
+			}
+		},
+		None => None
+	}
+}
+
+fn read_f64(reader: &Reader, tag: Tag) -> Option<f64> {
+	match reader.get_field(tag, false) {
+		Some(field) => {
+			match field.value {
+				Value::Rational(ref vec) if !vec.is_empty() && vec[0].denom != 0 => {
+					Some(vec[0].to_f64())
+				},
+				_ => None
+			}
+		},
+		None => None
+	}
+}
+
+/// Generates EXIF tags for all photos in source_id
+///
+/// Previously generated EXIF tags of the source are replaced, so it's safe
+/// to run again after rules change. Returns number of assigned tags.
+pub fn apply_to_source(connection: &my::Pool, policy: &PathPolicy, source_id: u64,
+	rules: &ExifTagRules) -> Result<u64, my::Error> {
+	tags::clear_source_tags(connection, source_id, tags::ORIGIN_EXIF)?;
+
+	let mut photos: Vec<(u64, String, Option<String>, Option<u32>)> = vec![];
+	for row in connection.prep_exec(r"
+		SELECT photos.id, CONCAT(`full_path`, `relative_path`),
+		photos.exif_camera, YEAR(photos.exif_datetime)
+		FROM `photos`, `sources`
+		WHERE sources.id = photos.source AND sources.id = :source",
+		params!{"source" => source_id})? {
+		photos.push(my::from_row(row?));
+	}
+
+	let mut assigned: u64 = 0;
+	for (id, full_path, camera, year) in photos.into_iter() {
+		let file = match policy.original(&full_path).ok()
+			.and_then(|path| File::open(path).ok()) {
+			Some(file) => file,
+			None => continue
+		};
+		let reader = match Reader::new(&mut BufReader::new(&file)) {
+			Ok(reader) => reader,
+			Err(_) => continue
+		};
+
+		assigned += tag_photo(connection, id, &reader, camera, year, rules)?;
+	}
+
+	Ok(assigned)
+}
+
+/// Assigns EXIF tags generated by rules to the photo with given id
+///
+/// Doesn't remove previously generated tags. Returns number of assigned tags.
+pub fn tag_photo(connection: &my::Pool, id: u64, reader: &Reader, camera: Option<String>,
+	year: Option<u32>, rules: &ExifTagRules) -> Result<u64, my::Error> {
+	let attributes = read_attributes(reader, camera, year);
+	let photo_tags = rules.tags_for(&attributes);
+	tags::assign_tags(connection, id, &photo_tags, tags::ORIGIN_EXIF)
+}
+
+/// Regenerates EXIF tags of the source :id using current rules
+pub fn apply_exif_tags(request: &mut Request) -> IronResult<Response> {
+	let source_id = request.extensions.get::<Router>().unwrap()
+		.find("id").unwrap_or("0")
+		.parse::<u64>().unwrap_or(0);
+
+	let (rules, policy) = {
+		let rwlock = request.get::<State<Settings>>().unwrap();
+		let settings = rwlock.read().unwrap();
+		(ExifTagRules::from_settings(&settings), PathPolicy::from_settings(&settings))
+	};
+	let connection = db::get_connection(request);
+
+	match apply_to_source(&connection, &policy, source_id, &rules) {
+		Ok(assigned) => {
+			let out_json = json!({
+				"status": "ok",
+				"assigned": assigned
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
@@ -0,0 +1,393 @@
+// Standard library includes
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// Library includes
+use iron::prelude::*;
+use iron::status;
+use iron::mime;
+use iron::response::WriteBody;
+use persistent::State;
+use mysql as my;
+use chrono::{Datelike, Local, Timelike};
+use crc32fast::Hasher;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+use albums;
+use search::{PhotoQuery, Pagination, find_photos, visible, param_string, param_list};
+use paths::{PathPolicy, decode_path};
+use Settings;
+
+/// Name of the size which stands for the original file
+const ORIGINAL_SIZE: &'static str = "original";
+
+/// How files are named inside of the archive
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FilenamePattern {
+	/// Name of the original file, like "IMG_0001.jpg"
+	Original,
+	/// Capture date and time, like "2023-04-16_07-20-03.jpg"
+	Date,
+	/// Photo id, like "1234.jpg"
+	Id
+}
+
+/// File to be put into the archive
+#[derive(Debug)]
+struct ZipEntry {
+	name: String,
+	path: PathBuf
+}
+
+/// Streams ZIP archive of photos
+///
+/// Selection is defined by one of `photo_ids`, `album_id` or search
+/// criteria accepted by /api/search. Optional parameters:
+/// * `size` - "original" (default) or rendition name like "medium"
+/// * `filename` - "original" (default), "date" or "id"
+///
+/// Files are stored without compression (JPEG doesn't compress anyway)
+/// and written directly to the client one by one, so the archive is never
+/// kept in memory.
+pub fn export_zip(request: &mut Request) -> IronResult<Response> {
+	let policy = {
+		let rwlock = request.get::<State<Settings>>().unwrap();
+		let settings = rwlock.read().unwrap();
+		PathPolicy::from_settings(&settings)
+	};
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+
+	let size = param_string(&params, "size")
+		.unwrap_or(ORIGINAL_SIZE.to_string());
+	if size != ORIGINAL_SIZE && !policy.is_rendition(&size) {
+		return Err(ApiError::bad_request("unknown size").into());
+	}
+
+	let pattern = match param_string(&params, "filename")
+		.unwrap_or("original".to_string())
+		.as_str() {
+		"original" => FilenamePattern::Original,
+		"date" => FilenamePattern::Date,
+		"id" => FilenamePattern::Id,
+		_ => {
+			return Err(ApiError::bad_request("filename should be original, date or id").into());
+		}
+	};
+
+	// Selected photo ids in the order they should appear in the archive
+	let album_id = param_string(&params, "album_id")
+		.and_then(|value| u64::from_str(&value).ok());
+	let photo_ids = param_list(&params, "photo_ids");
+
+	let ids: Vec<u64> = if let Some(album_id) = album_id {
+		albums::get_album_photos(&connection, album_id).map_err(ApiError::from)?
+	} else if !photo_ids.is_empty() {
+		photo_ids.iter().filter_map(|id| u64::from_str(id).ok()).collect()
+	} else if PhotoQuery::is_present_in(&params) {
+		let query = match PhotoQuery::from_params(&params) {
+			Ok(query) => query,
+			Err(message) => return Err(ApiError::bad_request(&message).into())
+		};
+		let pagination = Pagination { page: 1, per_page: None };
+		match find_photos(&connection, &query, &pagination) {
+			Ok((ids, _)) => ids,
+			Err(err) => return Err(ApiError::from(err).into())
+		}
+	} else {
+		return Err(
+			ApiError::bad_request("photo_ids, album_id or search query should be set").into()
+		);
+	};
+
+	let entries = match get_entries(&connection, &ids, &policy, &size, pattern) {
+		Ok(entries) => entries,
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	if entries.is_empty() {
+		return Err(ApiError::not_found("nothing to export").into());
+	}
+
+	let content_type = "application/zip".parse::<mime::Mime>().unwrap();
+	let body: Box<WriteBody> = Box::new(ZipStream { entries: entries });
+	let mut response = Response::with((content_type, status::Ok, body));
+	response.headers.set_raw("Content-Disposition",
+		vec![b"attachment; filename=\"photos.zip\"".to_vec()]);
+
+	Ok(response)
+}
+
+/// Resolves file paths and unique archive names of the photos
+///
+/// Photos whose files are missing or lead outside of allowed folders are
+/// skipped.
+fn get_entries(connection: &my::Pool, ids: &[u64], policy: &PathPolicy, size: &str,
+	pattern: FilenamePattern) -> Result<Vec<ZipEntry>, my::Error> {
+	let ids_list: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+
+	let mut photos: Vec<(u64, String, String, Option<String>)> = vec![];
+	for row in connection.prep_exec(format!(r"
+		SELECT photos.id, CONCAT(`full_path`, `relative_path`), `relative_path`,
+		DATE_FORMAT(`exif_datetime`, '%Y-%m-%d_%H-%i-%s')
+		FROM `photos`, `sources`
+		WHERE sources.id = photos.source AND FIND_IN_SET(photos.id, :ids) AND {}",
+		visible("photos")),
+		params!{"ids" => ids_list.join(",")})? {
+		photos.push(my::from_row(row?));
+	}
+
+	// Keep the order of selection
+	let positions: HashMap<u64, usize> = ids.iter()
+		.enumerate()
+		.map(|(position, id)| (*id, position))
+		.collect();
+	photos.sort_by_key(|photo| positions.get(&photo.0).cloned());
+
+	let mut used_names: HashSet<String> = HashSet::new();
+	let mut entries: Vec<ZipEntry> = vec![];
+
+	for (id, full_path, relative_path, datetime) in photos.into_iter() {
+		let path = if size == ORIGINAL_SIZE {
+			policy.original(&full_path)
+		} else {
+			policy.rendition(size, id)
+		};
+		let path = match path {
+			Ok(path) => path,
+			Err(err) => {
+				println!("Skipping photo {}: {}", id, err);
+				continue;
+			}
+		};
+
+		let extension = path.extension()
+			.and_then(|extension| extension.to_str())
+			.unwrap_or("jpg")
+			.to_string();
+
+		let name = match (pattern, datetime) {
+			(FilenamePattern::Original, _) => {
+				decode_path(&relative_path).file_stem()
+					.map(|stem| stem.to_string_lossy().into_owned())
+					.unwrap_or(String::new())
+			},
+			(FilenamePattern::Date, Some(datetime)) => datetime,
+			_ => String::new()
+		};
+		let name = if name.is_empty() { id.to_string() } else { name };
+
+		entries.push(ZipEntry {
+			name: unique_name(&mut used_names, &name, &extension),
+			path: path
+		});
+	}
+
+	Ok(entries)
+}
+
+/// Appends " (2)", " (3)"... to the name until it's unique in the archive
+fn unique_name(used_names: &mut HashSet<String>, name: &str, extension: &str)
+	-> String {
+	let mut candidate = format!("{}.{}", name, extension);
+	let mut counter = 2;
+
+	while used_names.contains(&candidate.to_lowercase()) {
+		candidate = format!("{} ({}).{}", name, counter, extension);
+		counter += 1;
+	}
+
+	used_names.insert(candidate.to_lowercase());
+	candidate
+}
+
+/// Response body writing ZIP archive entry by entry
+struct ZipStream {
+	entries: Vec<ZipEntry>
+}
+
+/// Central directory record of already written entry
+struct CentralRecord {
+	name: String,
+	crc: u32,
+	size: u32,
+	offset: u64
+}
+
+/// Writer keeping track of number of bytes written
+struct CountingWriter<'a> {
+	inner: &'a mut Write,
+	count: u64
+}
+
+impl<'a> Write for CountingWriter<'a> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = self.inner.write(buf)?;
+		self.count += written as u64;
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl WriteBody for ZipStream {
+	fn write_body(&mut self, res: &mut Write) -> io::Result<()> {
+		let mut out = CountingWriter { inner: res, count: 0 };
+		let (time, date) = dos_datetime();
+		let mut records: Vec<CentralRecord> = vec![];
+
+		for entry in self.entries.iter() {
+			// Missing files are skipped before anything is written for them
+			let mut file = match File::open(&entry.path) {
+				Ok(file) => file,
+				Err(err) => {
+					println!("Unable to export {:?}: {:?}", entry.path, err);
+					continue;
+				}
+			};
+
+			let offset = out.count;
+
+			// Local file header. CRC and sizes follow the data in
+			// data descriptor as they are not known in advance.
+			write_u32(&mut out, 0x04034b50)?;
+			write_u16(&mut out, 20)?;
+			write_u16(&mut out, 0x0808)?;
+			write_u16(&mut out, 0)?;
+			write_u16(&mut out, time)?;
+			write_u16(&mut out, date)?;
+			write_u32(&mut out, 0)?;
+			write_u32(&mut out, 0)?;
+			write_u32(&mut out, 0)?;
+			write_u16(&mut out, entry.name.len() as u16)?;
+			write_u16(&mut out, 0)?;
+			out.write_all(entry.name.as_bytes())?;
+
+			let mut hasher = Hasher::new();
+			let mut size: u64 = 0;
+			let mut buffer = [0u8; 64 * 1024];
+			loop {
+				let read = file.read(&mut buffer)?;
+				if read == 0 {
+					break;
+				}
+				hasher.update(&buffer[..read]);
+				out.write_all(&buffer[..read])?;
+				size += read as u64;
+			}
+			let crc = hasher.finalize();
+
+			// Data descriptor
+			write_u32(&mut out, 0x08074b50)?;
+			write_u32(&mut out, crc)?;
+			write_u32(&mut out, size as u32)?;
+			write_u32(&mut out, size as u32)?;
+
+			records.push(CentralRecord {
+				name: entry.name.clone(),
+				crc: crc,
+				size: size as u32,
+				offset: offset
+			});
+		}
+
+		// Central directory. Offsets above 4GB go to ZIP64 extra field.
+		let central_offset = out.count;
+		for record in records.iter() {
+			let zip64 = record.offset >= 0xFFFFFFFF;
+
+			write_u32(&mut out, 0x02014b50)?;
+			write_u16(&mut out, 45)?;
+			write_u16(&mut out, if zip64 { 45 } else { 20 })?;
+			write_u16(&mut out, 0x0808)?;
+			write_u16(&mut out, 0)?;
+			write_u16(&mut out, time)?;
+			write_u16(&mut out, date)?;
+			write_u32(&mut out, record.crc)?;
+			write_u32(&mut out, record.size)?;
+			write_u32(&mut out, record.size)?;
+			write_u16(&mut out, record.name.len() as u16)?;
+			write_u16(&mut out, if zip64 { 12 } else { 0 })?;
+			write_u16(&mut out, 0)?;
+			write_u16(&mut out, 0)?;
+			write_u16(&mut out, 0)?;
+			write_u32(&mut out, 0)?;
+			write_u32(&mut out, if zip64 { 0xFFFFFFFF } else { record.offset as u32 })?;
+			out.write_all(record.name.as_bytes())?;
+			if zip64 {
+				write_u16(&mut out, 0x0001)?;
+				write_u16(&mut out, 8)?;
+				write_u64(&mut out, record.offset)?;
+			}
+		}
+		let central_size = out.count - central_offset;
+		let entries_count = records.len() as u64;
+
+		let zip64 = central_offset >= 0xFFFFFFFF ||
+			central_size >= 0xFFFFFFFF ||
+			entries_count >= 0xFFFF;
+
+		if zip64 {
+			// ZIP64 end of central directory record and its locator
+			let zip64_offset = out.count;
+			write_u32(&mut out, 0x06064b50)?;
+			write_u64(&mut out, 44)?;
+			write_u16(&mut out, 45)?;
+			write_u16(&mut out, 45)?;
+			write_u32(&mut out, 0)?;
+			write_u32(&mut out, 0)?;
+			write_u64(&mut out, entries_count)?;
+			write_u64(&mut out, entries_count)?;
+			write_u64(&mut out, central_size)?;
+			write_u64(&mut out, central_offset)?;
+
+			write_u32(&mut out, 0x07064b50)?;
+			write_u32(&mut out, 0)?;
+			write_u64(&mut out, zip64_offset)?;
+			write_u32(&mut out, 1)?;
+		}
+
+		// End of central directory record
+		write_u32(&mut out, 0x06054b50)?;
+		write_u16(&mut out, 0)?;
+		write_u16(&mut out, 0)?;
+		write_u16(&mut out, entries_count.min(0xFFFF) as u16)?;
+		write_u16(&mut out, entries_count.min(0xFFFF) as u16)?;
+		write_u32(&mut out, central_size.min(0xFFFFFFFF) as u32)?;
+		write_u32(&mut out, central_offset.min(0xFFFFFFFF) as u32)?;
+		write_u16(&mut out, 0)?;
+
+		out.flush()
+	}
+}
+
+/// Current local time in MS-DOS format used by ZIP
+fn dos_datetime() -> (u16, u16) {
+	let now = Local::now();
+	let time = (now.hour() << 11) | (now.minute() << 5) | (now.second() / 2);
+	let date = (((now.year() - 1980).max(0) as u32) << 9) |
+		(now.month() << 5) | now.day();
+	(time as u16, date as u16)
+}
+
+fn write_u16(out: &mut Write, value: u16) -> io::Result<()> {
+	out.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+fn write_u32(out: &mut Write, value: u32) -> io::Result<()> {
+	write_u16(out, value as u16)?;
+	write_u16(out, (value >> 16) as u16)
+}
+
+fn write_u64(out: &mut Write, value: u64) -> io::Result<()> {
+	write_u32(out, value as u32)?;
+	write_u32(out, (value >> 32) as u32)
+}
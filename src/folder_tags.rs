@@ -0,0 +1,178 @@
+// Standard library includes
+use std::collections::HashMap;
+use std::path::Component;
+
+// Library includes
+use router::Router;
+use iron::prelude::*;
+use iron::status;
+use persistent::State;
+use mysql as my;
+use regex::Regex;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::ApiError;
+use tags;
+use paths::decode_path;
+use Settings;
+
+/// Rules turning folder names into tags
+///
+/// Configured in settings:
+/// * `folder_tag_rules` - one rule per line: `regex => template`. Template
+///   may refer to capture groups as `$1` and produce several tags separated
+///   by commas. First matching rule wins, folder without matching rule
+///   becomes a tag as is.
+/// * `folder_tag_ignore` - comma-separated folder names which never become
+///   tags, case-insensitive. For example "DCIM, Camera".
+/// * `folder_tag_years` - "true" to turn folders like "2019" into "year/2019"
+#[derive(Debug)]
+pub struct FolderTagRules {
+	rules: Vec<(Regex, String)>,
+	ignore: Vec<String>,
+	years: bool
+}
+
+impl FolderTagRules {
+	/// Reads rules from settings. Invalid regexes are reported and skipped.
+	pub fn from_settings(settings: &HashMap<String, String>) -> FolderTagRules {
+		let mut rules: Vec<(Regex, String)> = vec![];
+
+		for line in settings.get("folder_tag_rules")
+			.map(|value| value.as_str())
+			.unwrap_or("")
+			.lines() {
+			let line = line.trim();
+			if line.is_empty() {
+				continue;
+			}
+
+			let mut parts = line.splitn(2, "=>");
+			let pattern = parts.next().unwrap_or("").trim();
+			let template = parts.next().unwrap_or("").trim();
+
+			match Regex::new(pattern) {
+				Ok(regex) => rules.push((regex, template.to_string())),
+				Err(err) => println!("Invalid folder tag rule {:?}: {}", line, err)
+			}
+		}
+
+		let ignore = settings.get("folder_tag_ignore")
+			.map(|value| value.as_str())
+			.unwrap_or("")
+			.split(',')
+			.map(|name| name.trim().to_lowercase())
+			.filter(|name| !name.is_empty())
+			.collect();
+
+		let years = settings.get("folder_tag_years")
+			.map(|value| value == "true")
+			.unwrap_or(false);
+
+		FolderTagRules { rules: rules, ignore: ignore, years: years }
+	}
+
+	/// Produces tags for a photo from folders of its stored relative path
+	pub fn tags_for(&self, relative_path: &str) -> Vec<String> {
+		let mut result: Vec<String> = vec![];
+
+		let relative_path = decode_path(relative_path);
+		let folders = relative_path.parent()
+			.map(|parent| parent.components().collect::<Vec<Component>>())
+			.unwrap_or(vec![]);
+
+		for component in folders.iter() {
+			let folder = match *component {
+				Component::Normal(name) => name.to_string_lossy().trim().to_string(),
+				_ => continue
+			};
+			if folder.is_empty() || self.ignore.contains(&folder.to_lowercase()) {
+				continue;
+			}
+
+			for tag in self.folder_tags(&folder).into_iter() {
+				if !tag.is_empty() && !result.contains(&tag) {
+					result.push(tag);
+				}
+			}
+		}
+
+		result
+	}
+
+	/// Produces tags for a single folder name
+	fn folder_tags(&self, folder: &str) -> Vec<String> {
+		if self.years && folder.len() == 4 &&
+			folder.chars().all(|c| c.is_ascii_digit()) &&
+			(folder.starts_with("19") || folder.starts_with("20")) {
+			return vec![format!("year/{}", folder)];
+		}
+
+		for &(ref regex, ref template) in self.rules.iter() {
+			if let Some(captures) = regex.captures(folder) {
+				let mut expanded = String::new();
+				captures.expand(template, &mut expanded);
+				return expanded.split(',')
+					.map(|tag| tag.trim().to_string())
+					.collect();
+			}
+		}
+
+		vec![folder.to_string()]
+	}
+}
+
+/// Generates folder tags for all photos in source_id
+///
+/// Previously generated folder tags of the source are replaced, manual and
+/// imported tags stay untouched. Returns number of assigned tags.
+pub fn apply_to_source(connection: &my::Pool, source_id: u64, rules: &FolderTagRules)
+	-> Result<u64, my::Error> {
+	tags::clear_source_tags(connection, source_id, tags::ORIGIN_FOLDER)?;
+
+	let mut photos: Vec<(u64, String)> = vec![];
+	for row in connection.prep_exec(r"
+		SELECT `id`, `relative_path` FROM `photos` WHERE `source` = :source",
+		params!{"source" => source_id})? {
+		photos.push(my::from_row(row?));
+	}
+
+	let mut assigned: u64 = 0;
+	for (id, relative_path) in photos.into_iter() {
+		let photo_tags = rules.tags_for(&relative_path);
+		assigned += tags::assign_tags(connection, id, &photo_tags, tags::ORIGIN_FOLDER)?;
+	}
+
+	Ok(assigned)
+}
+
+/// Regenerates folder tags of the source :id using current settings
+pub fn apply_folder_tags(request: &mut Request) -> IronResult<Response> {
+	let source_id = request.extensions.get::<Router>().unwrap()
+		.find("id").unwrap_or("0")
+		.parse::<u64>().unwrap_or(0);
+
+	let rules = {
+		let rwlock = request.get::<State<Settings>>().unwrap();
+		let settings = rwlock.read().unwrap();
+		FolderTagRules::from_settings(&settings)
+	};
+	let connection = db::get_connection(request);
+
+	match apply_to_source(&connection, source_id, &rules) {
+		Ok(assigned) => {
+			let out_json = json!({
+				"status": "ok",
+				"assigned": assigned
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
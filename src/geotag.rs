@@ -0,0 +1,296 @@
+// Standard library includes
+use std::io::{BufReader, Read};
+use std::str::FromStr;
+
+// Library includes
+use iron::prelude::*;
+use iron::status;
+use params::Value;
+use mysql as my;
+use chrono::{DateTime, NaiveDateTime};
+use xml::reader::{EventReader, XmlEvent};
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+use search::{param_string, param_number};
+
+/// Maximum distance in time (seconds) between a photo and the closest
+/// track point for the position to be trusted.
+const DEFAULT_MAX_GAP: i64 = 600;
+
+/// Single point of a GPX track
+#[derive(Debug, Clone)]
+struct TrackPoint {
+	timestamp: i64,
+	latitude: f64,
+	longitude: f64,
+	altitude: f64
+}
+
+/// Geotags photos using uploaded GPX track
+///
+/// Accepts multipart form with next fields:
+/// * `gpx` - GPX file with one or more tracks
+/// * `source_id` or `photo_ids` (comma-separated) - photos to geotag
+/// * `offset` - seconds to add to camera clock to get UTC time
+/// * `max_gap` - optional, seconds between photo and track point (600)
+/// * `overwrite` - optional, replace coordinates read from EXIF too
+///
+/// Interpolated coordinates are saved to the same columns as EXIF GPS data
+/// and marked with `gps_derived` flag.
+pub fn geotag_photos(request: &mut Request) -> IronResult<Response> {
+	let params = request_params(request)?;
+
+	let source_id: u64 = param_number(&params, "source_id")
+		.map_err(|message| ApiError::bad_request(&message))?
+		.unwrap_or(0);
+	let photo_ids: String = param_string(&params, "photo_ids")
+		.unwrap_or(String::new());
+	let offset: i64 = param_number(&params, "offset")
+		.map_err(|message| ApiError::bad_request(&message))?
+		.unwrap_or(0);
+	let max_gap: i64 = param_number(&params, "max_gap")
+		.map_err(|message| ApiError::bad_request(&message))?
+		.unwrap_or(DEFAULT_MAX_GAP);
+	let overwrite = param_string(&params, "overwrite")
+		.map(|value| value == "true" || value == "1")
+		.unwrap_or(false);
+
+	if source_id == 0 && photo_ids.is_empty() {
+		return Err(ApiError::bad_request("source_id or photo_ids should be set").into());
+	}
+
+	let track = match params.find(&["gpx"]) {
+		Some(&Value::File(ref file)) => {
+			match file.open() {
+				Ok(file) => read_track(BufReader::new(file)),
+				Err(_) => vec![]
+			}
+		},
+		_ => {
+			return Err(ApiError::bad_request("gpx file should be uploaded").into());
+		}
+	};
+
+	if track.is_empty() {
+		return Err(
+			ApiError::bad_request("GPX file contains no timestamped track points").into()
+		);
+	}
+
+	let connection = db::get_connection(request);
+	let photos = get_photo_timestamps(&connection, source_id, &photo_ids, overwrite);
+
+	let mut tagged: u64 = 0;
+	let mut skipped: u64 = 0;
+
+	for &(id, timestamp) in photos.iter() {
+		match interpolate(&track, timestamp + offset, max_gap) {
+			Some(point) => {
+				let result = connection.prep_exec(r"
+				     UPDATE `photos`
+				     SET   `exif_latitude`  = :latitude,
+				           `exif_longitude` = :longitude,
+				           `exif_altitude`  = :altitude,
+				           `gps_derived`    = 1
+				     WHERE `id` = :id",
+				params!{
+					"id" => id,
+					"latitude" => point.latitude,
+					"longitude" => point.longitude,
+					"altitude" => point.altitude
+				});
+
+				match result {
+					Ok(_) => tagged += 1,
+					Err(err) => {
+						println!("{:?}", err);
+						skipped += 1;
+					}
+				}
+			},
+			None => skipped += 1
+		}
+	}
+
+	let out_json = json!({
+		"status": "ok",
+		"track_points": track.len(),
+		"tagged": tagged,
+		"skipped": skipped
+	});
+
+	Ok(
+		Response::with(
+			(status::Ok, to_string_pretty(&out_json).unwrap())
+		)
+	)
+}
+
+/// Selects photos to be geotagged together with their capture timestamps
+///
+/// Camera clock is stored without timezone, so timestamp is returned as if
+/// it was UTC. Caller applies the clock offset.
+fn get_photo_timestamps(connection: &my::Pool, source_id: u64, photo_ids: &str,
+	overwrite: bool) -> Vec<(u64, i64)> {
+	let result = connection.prep_exec(r"
+		SELECT `id`, DATE_FORMAT(`exif_datetime`, '%Y-%m-%d %H:%i:%s')
+		FROM `photos`
+		WHERE `exif_datetime` IS NOT NULL AND
+		(`source` = :source_id OR FIND_IN_SET(`id`, :photo_ids)) AND
+		(:overwrite OR `gps_derived` = 1 OR
+		 (IFNULL(`exif_latitude`, 0) = 0 AND IFNULL(`exif_longitude`, 0) = 0))",
+		params!{
+			"source_id" => source_id,
+			"photo_ids" => photo_ids,
+			"overwrite" => overwrite
+		}
+	);
+
+	let mut photos: Vec<(u64, i64)> = vec![];
+
+	match result {
+		Ok(result) => {
+			result.for_each(|row| {
+				match row {
+					Ok(row) => {
+						let (id, datetime): (u64, String) = my::from_row(row);
+						match NaiveDateTime::parse_from_str(
+							&datetime, "%Y-%m-%d %H:%M:%S") {
+							Ok(datetime) => photos.push((id, datetime.timestamp())),
+							Err(_) => {}
+						}
+					},
+					Err(_) => {}
+				}
+			});
+		},
+		Err(err) => println!("{:?}", err)
+	}
+
+	photos
+}
+
+/// Reads all timestamped points of all tracks in GPX document
+///
+/// Points are returned sorted by time. Points without `<time>` are useless
+/// for geotagging and skipped.
+fn read_track<R: Read>(source: R) -> Vec<TrackPoint> {
+	let mut points: Vec<TrackPoint> = vec![];
+
+	// Point being read at the moment and name of the element inside it
+	let mut current: Option<(f64, f64, f64, Option<i64>)> = None;
+	let mut element = String::new();
+
+	for event in EventReader::new(source) {
+		match event {
+			Ok(XmlEvent::StartElement { name, attributes, .. }) => {
+				if name.local_name == "trkpt" {
+					let mut latitude = None;
+					let mut longitude = None;
+					for attribute in attributes.iter() {
+						match attribute.name.local_name.as_str() {
+							"lat" => latitude = f64::from_str(&attribute.value).ok(),
+							"lon" => longitude = f64::from_str(&attribute.value).ok(),
+							_ => {}
+						}
+					}
+					current = match (latitude, longitude) {
+						(Some(latitude), Some(longitude)) => {
+							Some((latitude, longitude, 0.0, None))
+						},
+						_ => None
+					};
+				}
+				element = name.local_name;
+			},
+			Ok(XmlEvent::Characters(text)) => {
+				if let Some(ref mut point) = current {
+					match element.as_str() {
+						"ele" => {
+							point.2 = f64::from_str(text.trim()).unwrap_or(0.0);
+						},
+						"time" => {
+							point.3 = DateTime::parse_from_rfc3339(text.trim())
+								.map(|time| time.timestamp())
+								.ok();
+						},
+						_ => {}
+					}
+				}
+			},
+			Ok(XmlEvent::EndElement { name }) => {
+				if name.local_name == "trkpt" {
+					if let Some((latitude, longitude, altitude, Some(timestamp)))
+						= current {
+						points.push(TrackPoint {
+							timestamp: timestamp,
+							latitude: latitude,
+							longitude: longitude,
+							altitude: altitude
+						});
+					}
+					current = None;
+				}
+				element = String::new();
+			},
+			Ok(_) => {},
+			Err(err) => {
+				println!("Unable to parse GPX: {:?}", err);
+				break;
+			}
+		}
+	}
+
+	points.sort_by_key(|point| point.timestamp);
+	points
+}
+
+/// Calculates position at given moment of time
+///
+/// Position is linearly interpolated between two closest track points.
+/// If track has a gap longer than `max_gap` seconds around the moment, the
+/// closest point is used if it's near enough, otherwise None is returned.
+fn interpolate(track: &[TrackPoint], timestamp: i64, max_gap: i64)
+	-> Option<TrackPoint> {
+	let index = match track.binary_search_by_key(&timestamp, |p| p.timestamp) {
+		Ok(index) => return Some(track[index].clone()),
+		Err(index) => index
+	};
+
+	let before = if index > 0 { track.get(index - 1) } else { None };
+	let after = track.get(index);
+
+	match (before, after) {
+		(Some(before), Some(after))
+			if after.timestamp - before.timestamp <= max_gap => {
+			let ratio = (timestamp - before.timestamp) as f64 /
+				(after.timestamp - before.timestamp) as f64;
+
+			Some(TrackPoint {
+				timestamp: timestamp,
+				latitude: before.latitude +
+					(after.latitude - before.latitude) * ratio,
+				longitude: before.longitude +
+					(after.longitude - before.longitude) * ratio,
+				altitude: before.altitude +
+					(after.altitude - before.altitude) * ratio
+			})
+		},
+		_ => {
+			// Photo is outside of the track or inside a gap
+			let closest = vec![before, after].into_iter()
+				.filter_map(|point| point)
+				.min_by_key(|point| (point.timestamp - timestamp).abs());
+
+			match closest {
+				Some(point) if (point.timestamp - timestamp).abs() <= max_gap => {
+					Some(point.clone())
+				},
+				_ => None
+			}
+		}
+	}
+}
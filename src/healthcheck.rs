@@ -1,7 +1,15 @@
 // Library includes
 use iron::prelude::*;
 use iron::status;
+use serde_json::to_string_pretty;
 
 pub fn get_handler(_request: &mut Request) -> IronResult<Response> {
-	Ok(Response::with((status::Ok, "ok")))
+	let out_json = json!({
+		"status": "ok"
+	});
+	Ok(
+		Response::with(
+			(status::Ok, to_string_pretty(&out_json).unwrap())
+		)
+	)
 }
\ No newline at end of file
@@ -12,14 +12,20 @@ use persistent;
 
 // Local includes
 use db;
+use error::ApiError;
+use paths::PathPolicy;
+use search::visible;
 use Settings;
 
 // This handler serves image of requested size
 pub fn get(request: &mut Request) -> IronResult<Response> {
 	// Read global state
-	let rwlock = request.get::<State<Settings>>().unwrap();
-	let settings = rwlock.read().unwrap();
-	let gallery_folder = settings["gallery_folder"].as_str();
+	let policy = {
+		let rwlock = request.get::<State<Settings>>().unwrap();
+		let settings = rwlock.read().unwrap();
+		PathPolicy::from_settings(&settings)
+	};
+	let connection = db::get_connection(request);
 	
 	// Get url params
 	let ref id = request.extensions.get::<Router>().unwrap()
@@ -27,56 +33,56 @@ pub fn get(request: &mut Request) -> IronResult<Response> {
 	let ref size = request.extensions.get::<Router>().unwrap()
 	.find("size").unwrap_or("0");
 
+	if !policy.is_rendition(size) {
+		return Err(ApiError::bad_request("unknown size").into());
+	}
+
 	// Check if photo exists
-	let connection = db::get_connection();
-	let result = connection.prep_exec(r"
+	let result = connection.prep_exec(format!(r"
 	    SELECT photos.id FROM `photos`
-	    WHERE photos.id = :id",
+	    WHERE photos.id = :id AND {}",
+	visible("photos")),
 	params!{"id" => id});
 
 	match result {
 		Ok(result) => {
 			let mut id: u64 = 0;
 			for row in result {
-				id = my::from_row(row.unwrap());
+				id = match row {
+					Ok(row) => my::from_row(row),
+					Err(err) => return Err(ApiError::from(err).into())
+				};
 				break;
 			}
 
 			if id == 0 {
-				Ok(Response::with((status::NotFound, "")))
+				Err(ApiError::not_found("photo not found").into())
 			} else {
 				// Read image file and return
-				match read_image(gallery_folder, id, size) {
-					Some(data) => {
+				match read_image(&policy, id, size) {
+					Ok(data) => {
 						use iron::mime;
     					let content_type = "image/jpeg".parse::<mime::Mime>().unwrap();
 						Ok(Response::with((content_type, status::Ok, data)))
 					},
-					None => {
-						Ok(Response::with((status::NotFound, "")))
-					},
+					Err(err) => Err(err.into())
 				}
 				
 			}
 		},
-		Err(_) => {
-			Ok(Response::with((status::InternalServerError, "")))
-		}
+		Err(err) => Err(ApiError::from(err).into())
 	}
 		
 }
 
-fn read_image(gallery_folder: &str, id: u64, size: &str) -> Option<Vec<u8>>{
+/// Reads rendition of the photo from gallery folder
+///
+/// Only configured sizes are served and the file should stay inside of
+/// the gallery folder.
+pub fn read_image(policy: &PathPolicy, id: u64, size: &str) -> Result<Vec<u8>, ApiError> {
 	let mut buffer: Vec<u8> = vec![];
-	let mut file = File::open(format!("{}/{}/{}.jpg", gallery_folder, size, id))
-	.unwrap();
+	let mut file = File::open(policy.rendition(size, id)?)?;
 
-	match file.read_to_end(&mut buffer){
-		Ok(_) => {
-				return Some(buffer);
-		},
-		Err(_) => {
-			return None;
-		}
-	}
+	file.read_to_end(&mut buffer)?;
+	Ok(buffer)
 }
\ No newline at end of file
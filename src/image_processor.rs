@@ -5,12 +5,13 @@ use std::str::FromStr;
 use iron::prelude::*;
 use iron::status;
 use persistent::State;
-use params::Params;
 use params::FromValue;
 use serde_json::to_string_pretty;
 
 // Local includes
+use error::{ApiError, request_params};
 use image_processor_pool::ImageProcessorPoolShared;
+use crawler_pool::CrawlerPoolShared;
 
 /// Creates thumbnails for images in source_path
 /// This handler accepts source_path_id and starts thread 
@@ -18,12 +19,15 @@ use image_processor_pool::ImageProcessorPoolShared;
 /// and creates thumbnails for them
 pub fn process_source_path(request: &mut Request) -> IronResult<Response> {
 
-	let params = request.get::<Params>().unwrap();
-	let source_id: u64 = u64::from_str(
-		String::from_value(&params["source_id"])
-		.unwrap()
-		.as_str()
-		).unwrap_or(0);
+	let params = request_params(request)?;
+	let source_id: u64 = params.find(&["source_id"])
+		.and_then(|value| String::from_value(value))
+		.and_then(|value| u64::from_str(&value).ok())
+		.unwrap_or(0);
+
+	if source_id == 0 {
+		return Err(ApiError::bad_request("source_id should be set").into());
+	}
 
 
 	let rwlock = request.get::<State<ImageProcessorPoolShared>>().unwrap();
@@ -42,23 +46,20 @@ pub fn process_source_path(request: &mut Request) -> IronResult<Response> {
 			)
 		},
 		Err(_) => {
-			let out_json = json!({
-				"status": "locked",
-				"hint": "try later"
-			});
-
-			Ok(
-				Response::with(
-					(status::Locked, to_string_pretty(&out_json).unwrap())
-				)
-			)
+			Err(ApiError::new(status::Locked, "locked",
+				"Image processor is busy, try later").into())
 		}
 	}
 
 }
 
+/// Provides status of the source `source_id`
+///
+/// Status is "done" once images are processed, otherwise "crawling",
+/// "crawled" or "crawl_failed". `crawl` holds progress of crawling while
+/// it's known, see CrawlerPool.
 pub fn process_status(request: &mut Request) -> IronResult<Response> {
-	let params = request.get::<Params>().unwrap();
+	let params = request_params(request)?;
 	let source_id: u64 = match params.find(&["source_id"]) {
 		Some(_) => {
 			u64::from_str(
@@ -72,48 +73,40 @@ pub fn process_status(request: &mut Request) -> IronResult<Response> {
 		}
 	};
 
-
 	match source_id {
 		0 => {
-			return Ok(
-				Response::with(
-					(status::BadRequest, "source_id should be set")
-				)
-			);
+			return Err(ApiError::bad_request("source_id should be set").into());
 		},
 		_  => ()
 	};
 
+	// Crawling goes first, images are processed after it
+	let crawl = {
+		let rwlock = request.get::<State<CrawlerPoolShared>>().unwrap();
+		let crawler_pool = rwlock.read().unwrap();
+		crawler_pool.status_of(source_id)
+	};
 
 	let rwlock = request.get::<State<ImageProcessorPoolShared>>().unwrap();
-	let mut image_processor_pool = rwlock.write().unwrap();
-
-	match image_processor_pool.status_of(source_id) {
-		true => {
-			let out_json = json!({
-				"status": "done"
-			});
-
-			Ok(
-				Response::with(
-					(status::Ok, to_string_pretty(&out_json).unwrap())
-				)
-			)
-		},
-		false => {
-			let out_json = json!({
-				"status": "unknown",
-				"hint": "Status not found. Maybe it's not ready yet"
-			});
-
-			Ok(
-				Response::with(
-					(status::NotFound, to_string_pretty(&out_json).unwrap())
-				)
-			)
+	let image_processor_pool = rwlock.read().unwrap();
+
+	let state = match (image_processor_pool.status_of(source_id), &crawl) {
+		(true, _) => "done",
+		(false, &Some(ref crawl)) if !crawl.is_finished() => "crawling",
+		(false, &Some(ref crawl)) if crawl.error.is_some() => "crawl_failed",
+		(false, &Some(_)) => "crawled",
+		(false, &None) => {
+			return Err(ApiError::not_found("Status not found. Maybe it's not ready yet").into());
 		}
-	}
-
+	};
 
-	
+	let out_json = json!({
+		"status": state,
+		"crawl": crawl
+	});
+	Ok(
+		Response::with(
+			(status::Ok, to_string_pretty(&out_json).unwrap())
+		)
+	)
 }
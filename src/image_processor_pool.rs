@@ -6,21 +6,31 @@ use std::thread::JoinHandle;
 use std::collections::HashMap;
 use std::sync::mpsc;
 use std::process::Command;
+use std::fs;
 use std::fs::File;
 use std::io::BufReader;
 use std::string::String;
 use std::io::Write;
+use std::io;
+use std::path::{Path, PathBuf};
 
 // Library includes
 use iron::typemap::Key;
 use rayon::prelude::*;
 use rayon::iter::IntoParallelIterator;
 use mysql as my;
-use exif::{Reader, Value, Tag};
+use exif::{Reader, Value, Tag, DateTime};
+use sha2::{Sha256, Digest};
+use imagelib;
+use imagelib::imageops::FilterType;
 
 // Local includes
-use db;
 use crawler;
+use crawl_filter;
+use paths::PathPolicy;
+use exif_tags;
+use exif_tags::ExifTagRules;
+use tags;
 
 #[derive(Debug)]
 pub struct ImageProcessorPool {
@@ -28,7 +38,7 @@ pub struct ImageProcessorPool {
 	thread: JoinHandle<()>,
 	job_sender: mpsc::SyncSender<Job>,
 	pub job_done_receiver: Arc<Mutex<mpsc::Receiver<JobDone>>>,
-	done_jobs: Vec<JobDone>
+	done_jobs: Mutex<Vec<JobDone>>
 }
 
 unsafe impl Sync for ImageProcessorPool {}
@@ -48,7 +58,7 @@ unsafe impl Sync for JobDone {}
 
 impl ImageProcessorPool {
 	/// Create a new ImageProcessorPool with only one working thread
-	pub fn new(settings: HashMap<String, String>) -> ImageProcessorPool {
+	pub fn new(settings: HashMap<String, String>, pool: my::Pool) -> ImageProcessorPool {
 
 		// Channel size = 0 means that there will be no bufferisation between
 		// threads. So nothing should remain inside the sync_channel. Jobs will
@@ -57,6 +67,9 @@ impl ImageProcessorPool {
 		let (job_sender, jobreceiver) = mpsc::sync_channel::<Job>(channel_size);
 		let (job_done_sender, job_done_receiver) = mpsc::channel::<JobDone>();
 		let thread = thread::spawn(move || {
+			let exif_tag_rules = ExifTagRules::from_settings(&settings);
+			let policy = PathPolicy::from_settings(&settings);
+
 			loop {
 				// Waiting for job from the receiving end of the channel
 				let job = jobreceiver.recv().unwrap();
@@ -64,26 +77,47 @@ impl ImageProcessorPool {
 				println!("ImageProcessorPool got a job; Processing images \
 					in source_id: {}", job.source_id);
 				
+				// Extracting EXIF location data and generating EXIF tags for
+				// specified source. Goes first as renditions are rotated by
+				// the read orientation.
+				let orientations = match ImageProcessorPool::process_gps(&pool, &policy,
+					job.source_id, &exif_tag_rules) {
+					Ok(orientations) => orientations,
+					Err(_) => {
+						println!("Unable to extract EXIF data in the source.");
+						HashMap::new()
+					}
+				};
+
 				// Creating thumbnails for specified source
-				match ImageProcessorPool::create_thumbs_in_source(
-					settings["gallery_folder"].clone(), job.source_id) {
+				match ImageProcessorPool::create_thumbs_in_source(&pool, &policy,
+					settings["gallery_folder"].clone(), job.source_id, &orientations) {
 					Ok(_) => {},
 					Err(_) => {
 						println!("Unable to process images in the source.");
 					}
 				}
 
-				// Extracting EXIF location data for specified source
-				match ImageProcessorPool::process_gps(job.source_id){
+				// Calculating content hashes to find exact duplicates
+				match ImageProcessorPool::process_hashes(&pool, &policy, job.source_id){
 					Ok(_) => {},
 					Err(_) => {
-						println!("Unable to extract EXIF data in the source.");
+						println!("Unable to calculate hashes in the source.");
+					}
+				}
+
+				// Calculating perceptual hashes to find similar photos
+				match ImageProcessorPool::process_phashes(&pool, &policy,
+					settings["gallery_folder"].clone(), job.source_id) {
+					Ok(_) => {},
+					Err(_) => {
+						println!("Unable to calculate perceptual hashes \
+							in the source.");
 					}
 				}
 
 				// Set source_id status to resized
-				let connection = db::get_connection();
-				 let _result = connection.prep_exec(r"
+				let _result = pool.prep_exec(r"
 				      UPDATE `sources` 
 				      SET   `status` = 'resized' 
 				      WHERE `id` = :source_id", 
@@ -103,7 +137,7 @@ impl ImageProcessorPool {
 			thread: thread,
 			job_sender: job_sender,
 			job_done_receiver: Arc::new(Mutex::new(job_done_receiver)),
-			done_jobs: vec![]
+			done_jobs: Mutex::new(vec![])
 		}
 	}
 
@@ -132,21 +166,18 @@ impl ImageProcessorPool {
 	/// // If you pass --test to Rustdoc, it will even test it for you!
 	/// let task_status = image_processing_pool::status_of(3);
 	/// ```
-	pub fn status_of(&mut self, source_id: u64) -> bool {
+	pub fn status_of(&self, source_id: u64) -> bool {
+		let mut done_jobs = self.done_jobs.lock().unwrap_or_else(|err| err.into_inner());
+
 		// Getting all JobDone's from channel
-		loop {
-			match self.job_done_receiver.lock().unwrap().try_recv() {
-				Ok(job_done) => {
-					self.done_jobs.push(job_done);
-				},
-				Err(_) => {
-					break;
-				}
-			}
+		let job_done_receiver = self.job_done_receiver.lock()
+			.unwrap_or_else(|err| err.into_inner());
+		while let Ok(job_done) = job_done_receiver.try_recv() {
+			done_jobs.push(job_done);
 		}
-		
+
 		//Searching for requested source_id
-		match self.done_jobs
+		match done_jobs
 			.iter()
 			.find(|&job_done| job_done.source_id == source_id) {
 			Some(_) => true,
@@ -312,30 +343,216 @@ impl ImageProcessorPool {
 	}
 
 
+	/// Gets capture date and time (DateTimeOriginal) in MySQL DATETIME format
+	/// For example: "2023-04-16 07:20:03". This is the camera clock,
+	/// so it carries no timezone information.
+	///
+	/// # Arguments 
+	/// * `reader` - EXIF Reader object from kamadak-exif library
+	fn read_datetime(reader: &Reader) -> Option<String> {
+		if let Some(field) = reader.get_field(Tag::DateTimeOriginal, false) {
+			match field.value {
+				Value::Ascii(ref vec) if !vec.is_empty() => {
+					if let Ok(datetime) = DateTime::from_ascii(vec[0]) {
+						return Some(format!(
+							"{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+							datetime.year, datetime.month, datetime.day,
+							datetime.hour, datetime.minute, datetime.second
+						));
+					}
+				},
+				_ => {},
+			}
+		}
+		None
+	}
+
+
+	/// Gets EXIF Orientation, 1 (upright) when it's missing or invalid
+	///
+	/// # Arguments 
+	/// * `reader` - EXIF Reader object from kamadak-exif library
+	fn read_orientation(reader: &Reader) -> u32 {
+		let orientation = match reader.get_field(Tag::Orientation, false) {
+			Some(field) => {
+				match field.value {
+					Value::Short(ref vec) if !vec.is_empty() => vec[0] as u32,
+					_ => 1,
+				}
+			},
+			None => 1,
+		};
+		if orientation >= 1 && orientation <= 8 { orientation } else { 1 }
+	}
+
+
+	/// Gets width and height of the photo as displayed, i.e. swapped for
+	/// orientations rotating it by 90 degrees. Dimensions are taken from
+	/// the JPEG frame header or, if it can't be read, from EXIF.
+	///
+	/// # Arguments 
+	/// * `reader` - EXIF Reader object from kamadak-exif library
+	/// * `frame` - dimensions from the JPEG frame header
+	fn read_dimensions(reader: &Reader, frame: Option<(u32, u32)>) -> Option<(u32, u32)> {
+		let read_uint = |tag: Tag| -> Option<u32> {
+			match reader.get_field(tag, false) {
+				Some(field) => {
+					match field.value {
+						Value::Short(ref vec) if !vec.is_empty() => Some(vec[0] as u32),
+						Value::Long(ref vec) if !vec.is_empty() => Some(vec[0]),
+						_ => None,
+					}
+				},
+				None => None,
+			}
+		};
+
+		// Frame header describes the actual pixels, EXIF tags may be stale
+		// after editing
+		let dimensions = frame.or_else(|| {
+			match (read_uint(Tag::PixelXDimension), read_uint(Tag::PixelYDimension)) {
+				(Some(width), Some(height)) => Some((width, height)),
+				_ => None
+			}
+		});
+
+		// Orientations 5-8 rotate the picture by 90 degrees
+		match ImageProcessorPool::read_orientation(reader) {
+			5 | 6 | 7 | 8 => dimensions.map(|(width, height)| (height, width)),
+			_ => dimensions
+		}
+	}
+
+
+	/// Gets camera name as "Make Model", for example "Canon EOS 80D".
+	/// Make is omitted if the model already starts with it.
+	///
+	/// # Arguments 
+	/// * `reader` - EXIF Reader object from kamadak-exif library
+	fn read_camera(reader: &Reader) -> Option<String> {
+		let read_ascii = |tag: Tag| -> String {
+			match reader.get_field(tag, false) {
+				Some(field) => {
+					match field.value {
+						Value::Ascii(ref vec) if !vec.is_empty() => {
+							String::from_utf8_lossy(vec[0])
+								.trim_matches(char::from(0))
+								.trim()
+								.to_string()
+						},
+						_ => String::new(),
+					}
+				},
+				None => String::new(),
+			}
+		};
+
+		let make = read_ascii(Tag::Make);
+		let model = read_ascii(Tag::Model);
+
+		if model.is_empty() {
+			return if make.is_empty() { None } else { Some(make) };
+		}
+		if make.is_empty() ||
+			model.to_lowercase().starts_with(&make.to_lowercase()) {
+			return Some(model);
+		}
+		Some(format!("{} {}", make, model))
+	}
+
+
 	/// Extracts GPS EXIF data from photos in source_id
-	fn process_gps(source_id: u64) -> Result<u64, bool> {
+	///
+	/// Coordinates marked as derived (interpolated from a GPX track by the
+	/// geotag module) are kept untouched. Width and height are stored as
+	/// the photo is displayed, respecting EXIF Orientation. Tags are
+	/// generated from EXIF attributes by exif_tag_rules in the same pass.
+	///
+	/// Returns EXIF Orientation of photos which have it, so renditions are
+	/// created without reading EXIF again.
+	fn process_gps(connection: &my::Pool, policy: &PathPolicy, source_id: u64,
+		exif_tag_rules: &ExifTagRules) -> Result<HashMap<u64, u32>, bool> {
 		println!("Extracting EXIF!");
-		let images = crawler::get_photos(source_id);
+		let images = match crawler::get_photos(connection, source_id) {
+			Ok(images) => images,
+			Err(err) => {
+				println!("{:?}", err);
+				return Err(false);
+			}
+		};
+
+		// Tags are generated from the same EXIF read, the ones generated
+		// before are replaced
+		if let Err(err) = tags::clear_source_tags(connection, source_id, tags::ORIGIN_EXIF) {
+			println!("{:?}", err);
+		}
+
+		let mut orientations: HashMap<u64, u32> = HashMap::new();
 		images.into_iter().for_each(|(id, full_path)|{
 			// Open file
-			let file = File::open(&full_path).unwrap();
-			let reader = Reader::new(&mut BufReader::new(&file)).unwrap();
+			let path = match policy.original(&full_path) {
+				Ok(path) => path,
+				Err(err) => {
+					println!("Skipping {}: {}", full_path, err);
+					return;
+				}
+			};
+			let file = match File::open(&path) {
+				Ok(file) => file,
+				Err(err) => {
+					println!("Unable to open {}: {:?}", full_path, err);
+					return;
+				}
+			};
+			let frame = crawl_filter::jpeg_dimensions(&path);
+			let reader = match Reader::new(&mut BufReader::new(&file)) {
+				Ok(reader) => reader,
+				Err(err) => {
+					println!("Unable to read EXIF of {}: {:?}", full_path, err);
+					// Without EXIF there's no orientation, picture is
+					// displayed as stored
+					let result = connection.prep_exec(r"
+						UPDATE `photos` SET `width` = :width, `height` = :height
+						WHERE `id` = :id",
+						params!{
+							"id" => id,
+							"width" => frame.map(|(width, _)| width),
+							"height" => frame.map(|(_, height)| height)
+						});
+					if let Err(err) = result {
+						println!("{:?}", err);
+					}
+					return;
+				}
+			};
+
+			orientations.insert(id, ImageProcessorPool::read_orientation(&reader));
 
 			let latitude = ImageProcessorPool::read_latitude(&reader);
 			let longitude = ImageProcessorPool::read_longitude(&reader);
 			let altitude = ImageProcessorPool::read_altitude(&reader);
 			let date = ImageProcessorPool::read_gps_date(&reader);
 			let time = ImageProcessorPool::read_gps_time(&reader);
-			let connection = db::get_connection();
+			let datetime = ImageProcessorPool::read_datetime(&reader);
+			let camera = ImageProcessorPool::read_camera(&reader);
+			let camera_name = camera.clone();
+			let year = datetime.as_ref()
+				.and_then(|datetime| datetime.get(0..4))
+				.and_then(|year| year.parse::<u32>().ok());
+			let dimensions = ImageProcessorPool::read_dimensions(&reader, frame);
 
 			// Set image data
 			let _result = connection.prep_exec(r"
 			     UPDATE `photos` 
-			     SET   `exif_latitude` = :latitude,
-			           `exif_longitude` = :longitude,
-			           `exif_altitude`  = :altitude,
+			     SET   `exif_latitude` = IF(`gps_derived`, `exif_latitude`, :latitude),
+			           `exif_longitude` = IF(`gps_derived`, `exif_longitude`, :longitude),
+			           `exif_altitude`  = IF(`gps_derived`, `exif_altitude`, :altitude),
 			           `exif_gps_date`  = :date,
-			           `exif_gps_time`  = :time 
+			           `exif_gps_time`  = :time,
+			           `exif_datetime`  = :datetime,
+			           `exif_camera`    = :camera,
+			           `width`          = :width,
+			           `height`         = :height 
 			     WHERE `id` = :id", 
 			params!{
 				"id" => id,
@@ -343,23 +560,238 @@ impl ImageProcessorPool {
 				"longitude" => longitude,
 				"altitude" => altitude,
 				"date" => date,
-				"time" => time
+				"time" => time,
+				"datetime" => datetime,
+				"camera" => camera,
+				"width" => dimensions.map(|(width, _)| width),
+				"height" => dimensions.map(|(_, height)| height)
 			});
 
 			//TODO: Implement quesry result check
+
+			let tagged = exif_tags::tag_photo(connection, id, &reader, camera_name, year,
+				exif_tag_rules);
+			if let Err(err) = tagged {
+				println!("Unable to generate EXIF tags of {}: {:?}", full_path, err);
+			}
 		});
+		Ok(orientations)
+	}
+
+
+	/// Calculates SHA-256 of every original file in source_id
+	///
+	/// If an identical file already belongs to a group with chosen canonical
+	/// copy, the new photo is marked as duplicate of it right away.
+	fn process_hashes(connection: &my::Pool, policy: &PathPolicy, source_id: u64)
+		-> Result<u64, bool> {
+		let images = match crawler::get_photos(connection, source_id) {
+			Ok(images) => images,
+			Err(err) => {
+				println!("{:?}", err);
+				return Err(false);
+			}
+		};
+
+		images.into_par_iter().for_each(|(id, full_path)| {
+			let path = match policy.original(&full_path) {
+				Ok(path) => path,
+				Err(err) => {
+					println!("Skipping {}: {}", full_path, err);
+					return;
+				}
+			};
+			let hash = match ImageProcessorPool::hash_file(&path) {
+				Ok(hash) => hash,
+				Err(err) => {
+					println!("Unable to hash {}: {:?}", full_path, err);
+					return;
+				}
+			};
+
+			let _result = connection.prep_exec(r"
+			     UPDATE `photos` 
+			     SET   `content_hash` = :hash 
+			     WHERE `id` = :id", 
+			params!{
+				"id" => id,
+				"hash" => hash
+			});
+
+			let _result = connection.prep_exec(r"
+			     UPDATE `photos` AS `photo` 
+			     JOIN  `photos` AS `other` 
+			           ON  `other`.`content_hash` = `photo`.`content_hash` 
+			           AND `other`.`duplicate_of` IS NOT NULL 
+			     SET   `photo`.`duplicate_of` = `other`.`duplicate_of` 
+			     WHERE `photo`.`id` = :id 
+			     AND   `photo`.`id` <> `other`.`duplicate_of`", 
+			params!{"id" => id});
+		});
+
 		Ok(0)
 	}
 
+	/// Returns hex-encoded SHA-256 of file contents
+	fn hash_file(full_path: &Path) -> io::Result<String> {
+		let mut file = File::open(full_path)?;
+		let mut hasher = Sha256::new();
+		io::copy(&mut file, &mut hasher)?;
+		Ok(format!("{:x}", hasher.result()))
+	}
+
+
+	/// Calculates perceptual hash (dHash) of every photo in source_id
+	///
+	/// Medium rendition is used when it exists as it's much faster to decode
+	/// than the original. Its hash is usually within a small Hamming distance
+	/// of the hash of the original, not necessarily equal to it.
+	fn process_phashes(connection: &my::Pool, policy: &PathPolicy, gallery_folder: String,
+		source_id: u64) -> Result<u64, bool> {
+		let images = match crawler::get_photos(connection, source_id) {
+			Ok(images) => images,
+			Err(err) => {
+				println!("{:?}", err);
+				return Err(false);
+			}
+		};
+
+		images.into_par_iter().for_each(|(id, full_path)| {
+			let medium_path = format!("{}/medium/{}.jpg", gallery_folder, id);
+			let path = if Path::new(&medium_path).exists() {
+				PathBuf::from(medium_path)
+			} else {
+				match policy.original(&full_path) {
+					Ok(path) => path,
+					Err(err) => {
+						println!("Skipping {}: {}", full_path, err);
+						return;
+					}
+				}
+			};
+
+			let phash = match ImageProcessorPool::dhash(&path) {
+				Some(phash) => phash,
+				None => {
+					println!("Unable to calculate perceptual hash of {:?}", path);
+					return;
+				}
+			};
+
+			let _result = connection.prep_exec(r"
+			     UPDATE `photos` 
+			     SET   `phash` = :phash 
+			     WHERE `id` = :id", 
+			params!{
+				"id" => id,
+				"phash" => phash
+			});
+		});
+
+		Ok(0)
+	}
+
+	/// Calculates 64-bit difference hash of an image
+	///
+	/// Image is reduced to 9x8 grayscale pixels and every bit tells whether
+	/// a pixel is brighter than its right neighbour.
+	fn dhash(path: &Path) -> Option<u64> {
+		let image = match imagelib::open(path) {
+			Ok(image) => image,
+			Err(err) => {
+				println!("Unable to decode {:?}: {:?}", path, err);
+				return None;
+			}
+		};
+		let pixels = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+		let mut hash: u64 = 0;
+		for y in 0..8 {
+			for x in 0..8 {
+				hash <<= 1;
+				if pixels.get_pixel(x, y)[0] > pixels.get_pixel(x + 1, y)[0] {
+					hash |= 1;
+				}
+			}
+		}
+		Some(hash)
+	}
+
+
+	/// ImageMagick options turning an image stored with EXIF `orientation`
+	/// upright
+	fn orientation_options(orientation: u32) -> &'static [&'static str] {
+		match orientation {
+			2 => &["-flop"],
+			3 => &["-rotate", "180"],
+			4 => &["-flip"],
+			5 => &["-transpose"],
+			6 => &["-rotate", "90"],
+			7 => &["-transverse"],
+			8 => &["-rotate", "270"],
+			_ => &[]
+		}
+	}
+
+
+	/// Saves embedded EXIF thumbnail as the rendition
+	///
+	/// Thumbnail is stored the same way as the photo, so it's rotated
+	/// according to the photo's orientation. It's prepared in a temporary
+	/// file, so a failed rotation never leaves a sideways rendition.
+	fn save_thumbnail(thumbnail: &[u8], orientation: u32, rendition: &str) -> io::Result<()> {
+		let temporary = format!("{}.tmp.jpg", rendition);
+		let mut file = File::create(&temporary)?;
+		file.write_all(thumbnail)?;
+
+		let options = ImageProcessorPool::orientation_options(orientation);
+		if !options.is_empty() {
+			let output = Command::new("convert")
+				.arg(&temporary)
+				.args(options)
+				.arg(&temporary)
+				.output();
+			let error = match output {
+				Ok(ref output) if output.status.success() => None,
+				Ok(output) => Some(io::Error::new(io::ErrorKind::Other,
+					String::from_utf8_lossy(&output.stderr).into_owned())),
+				Err(err) => Some(err)
+			};
+			if let Some(err) = error {
+				let _ = fs::remove_file(&temporary);
+				return Err(err);
+			}
+		}
+
+		fs::rename(&temporary, rendition)
+	}
+
 
 	/// Creates thumbnail images for corresponding source folder
-	fn create_thumbs_in_source(gallery_folder: String, source_id: u64)
+	///
+	/// Renditions are rotated and flipped according to EXIF Orientation of
+	/// the original, so they're upright without any metadata.
+	fn create_thumbs_in_source(connection: &my::Pool, policy: &PathPolicy,
+		gallery_folder: String, source_id: u64, orientations: &HashMap<u64, u32>)
 		-> Result<u64, bool> {
 
-		let images = crawler::get_photos(source_id);
+		let images = match crawler::get_photos(connection, source_id) {
+			Ok(images) => images,
+			Err(err) => {
+				println!("{:?}", err);
+				return Err(false);
+			}
+		};
 
 		images.into_par_iter().for_each(|(id, full_path)| {
 			println!("Doing something for {:?}", full_path);
+			let full_path = match policy.original(&full_path) {
+				Ok(path) => path,
+				Err(err) => {
+					println!("Skipping {}: {}", full_path, err);
+					return;
+				}
+			};
 
 			// Create large image
 			// Command::new("convert")
@@ -384,36 +816,54 @@ impl ImageProcessorPool {
 			// 	.expect("failed to execute process");
 
 			// Instead of medium image we get JPEG thumbnail
-			match Command::new("exiftool")
+			let medium_path = format!("{}/medium/{}.jpg", gallery_folder, id);
+			let thumbnail = match Command::new("exiftool")
 				.arg("-b")
 				.arg("-ThumbnailImage")
 				.arg(&full_path)
 				.output() {
-					Ok(out) => {
-						if out.stdout.len() > 0 {
-							let mut file = File::create(
-								format!("{}/medium/{}.jpg", gallery_folder, id)
-							).unwrap();
-							file.write_all(&out.stdout).unwrap();
-						} else {
-								Command::new("convert")
-									.arg(&full_path)
-									.arg("-resize")
-									.arg("600x600")
-									.arg("-quality")
-									.arg("70")
-									.arg(format!("{}/medium/{}.jpg", gallery_folder, id))
-									.output()
-									.expect("failed to execute process");
-						}
-
-						
-					},
-					Err(_) => {
-
+					Ok(out) => out.stdout,
+					Err(err) => {
+						println!("Unable to run exiftool: {:?}", err);
+						vec![]
 					}
-
+				};
+
+			let orientation = orientations.get(&id).cloned().unwrap_or(1);
+			let saved = thumbnail.len() > 0 && match ImageProcessorPool::save_thumbnail(
+				&thumbnail, orientation, &medium_path) {
+				Ok(_) => true,
+				Err(err) => {
+					println!("Unable to rotate thumbnail of {:?}: {:?}", full_path, err);
+					false
 				}
+			};
+
+			let result = if saved {
+				Ok(())
+			} else {
+				// -auto-orient applies EXIF Orientation and resets it
+				Command::new("convert")
+					.arg(&full_path)
+					.arg("-auto-orient")
+					.arg("-resize")
+					.arg("600x600")
+					.arg("-quality")
+					.arg("70")
+					.arg(&medium_path)
+					.output()
+					.and_then(|output| {
+						if output.status.success() {
+							Ok(())
+						} else {
+							Err(io::Error::new(io::ErrorKind::Other,
+								String::from_utf8_lossy(&output.stderr).into_owned()))
+						}
+					})
+			};
+			if let Err(err) = result {
+				println!("Unable to create {}: {:?}", medium_path, err);
+			}
 
 			// Create small image (thumbnail)
 			// Command::new("convert")
@@ -13,9 +13,18 @@ extern crate rayon;
 extern crate config;
 extern crate persistent;
 extern crate exif;
+extern crate chrono;
+extern crate xml;
+extern crate sha2;
+extern crate crc32fast;
+extern crate regex;
+extern crate image as imagelib;
+extern crate hmac;
+extern crate pbkdf2;
 
 //DB connectivity
 mod db;
+mod error;
 
 mod image_processor_pool;
 
@@ -24,9 +33,28 @@ mod healthcheck;
 mod crawler;
 mod image_processor;
 mod image;
+mod geotag;
+mod duplicates;
+mod similar;
+mod albums;
+mod search;
+mod tags;
+mod smart_albums;
+mod shares;
+mod export;
+mod xmp;
+mod folder_tags;
+mod exif_tags;
+mod tag_suggestions;
+mod ratings;
+mod trash;
+mod crawler_pool;
+mod paths;
+mod crawl_filter;
 
 // Standard library includes
 use std::collections::HashMap;
+use std::process;
 
 // Library includes
 use iron::prelude::*;
@@ -36,9 +64,13 @@ use logger::Logger;
 use params::Params;
 use persistent::State;
 use iron::typemap::Key;
+use serde_json::to_string_pretty;
 
 // Local includes
 use image_processor_pool::{ImageProcessorPool, ImageProcessorPoolShared};
+use crawler_pool::{CrawlerPool, CrawlerPoolShared};
+use db::DbPool;
+use error::JsonErrors;
 
 #[derive(Copy, Clone)]
 pub struct Settings;
@@ -47,9 +79,14 @@ impl Key for Settings { type Value = HashMap<String, String>; }
 fn login_handler(request: &mut Request) -> IronResult<Response> {
 	println!("{:?}", request.get_ref::<Params>());
 
-	let response = "login".to_string();// + name;
-
-	Ok(Response::with((status::Ok, response)))
+	let out_json = json!({
+		"status": "ok"
+	});
+	Ok(
+		Response::with(
+			(status::Ok, to_string_pretty(&out_json).unwrap())
+		)
+	)
 }
 
 fn main() {
@@ -61,12 +98,22 @@ fn main() {
 		.merge(config::File::with_name("settings"))
 		.unwrap_or(&mut config::Config::default());
 
-	let settings = settings.try_into::<HashMap<String, String>>().unwrap_or_default();
+	let mut settings = settings.try_into::<HashMap<String, String>>().unwrap_or_default();
 	println!(
 		"Running with config: \n{:?}",
 		settings
 	);
 
+	// Connection pool shared by handlers and background workers
+	let pool = match db::create_pool(&settings) {
+		Ok(pool) => pool,
+		Err(err) => {
+			println!("Unable to connect to DB: {}", err);
+			process::exit(1);
+		}
+	};
+	paths::default_source_roots(&pool, &mut settings);
+
 	//Create router instance
 	let mut router = Router::new();
 	router.post("/api/add_source_path",
@@ -82,6 +129,198 @@ fn main() {
 		crawler::list_photos,
 		"list_photos"
 	);
+	router.get("/api/list_album_photos/:id",
+		albums::list_album_photos,
+		"list_album_photos"
+	);
+	router.get("/api/albums",
+		albums::list_albums,
+		"list_albums"
+	);
+	router.post("/api/albums",
+		albums::create_album,
+		"create_album"
+	);
+	router.get("/api/album/:id",
+		albums::get_album,
+		"get_album"
+	);
+	router.post("/api/album/:id",
+		albums::update_album,
+		"update_album"
+	);
+	router.delete("/api/album/:id",
+		albums::delete_album,
+		"delete_album"
+	);
+	router.post("/api/album/:id/photos",
+		albums::add_photos,
+		"add_album_photos"
+	);
+	router.delete("/api/album/:id/photos",
+		albums::remove_photos,
+		"remove_album_photos"
+	);
+	router.post("/api/album/:id/reorder",
+		albums::reorder_photos,
+		"reorder_album_photos"
+	);
+	router.get("/api/search",
+		search::search,
+		"search"
+	);
+	router.get("/api/list_smart_album_photos/:id",
+		smart_albums::list_smart_album_photos,
+		"list_smart_album_photos"
+	);
+	router.get("/api/smart_albums",
+		smart_albums::list_smart_albums,
+		"list_smart_albums"
+	);
+	router.post("/api/smart_albums",
+		smart_albums::create_smart_album,
+		"create_smart_album"
+	);
+	router.get("/api/smart_album/:id",
+		smart_albums::get_smart_album,
+		"get_smart_album"
+	);
+	router.post("/api/smart_album/:id",
+		smart_albums::update_smart_album,
+		"update_smart_album"
+	);
+	router.delete("/api/smart_album/:id",
+		smart_albums::delete_smart_album,
+		"delete_smart_album"
+	);
+	router.get("/api/shares",
+		shares::list_shares,
+		"list_shares"
+	);
+	router.post("/api/shares",
+		shares::create_share,
+		"create_share"
+	);
+	router.delete("/api/shares/:token",
+		shares::delete_share,
+		"delete_share"
+	);
+	router.get("/api/shared/:token",
+		shares::list_shared_photos,
+		"list_shared_photos"
+	);
+	router.get("/api/shared/:token/image/:id/:size",
+		shares::get_shared_image,
+		"get_shared_image"
+	);
+	router.get("/api/export_zip",
+		export::export_zip,
+		"export_zip"
+	);
+	router.post("/api/export_zip",
+		export::export_zip,
+		"export_zip_post"
+	);
+	router.post("/api/export_sidecars",
+		xmp::export_sidecars,
+		"export_sidecars"
+	);
+	router.post("/api/source/:id",
+		crawler::update_source,
+		"update_source"
+	);
+	router.delete("/api/source/:id",
+		crawler::delete_source,
+		"delete_source"
+	);
+	router.post("/api/source/:id/rescan",
+		crawler::rescan_source,
+		"rescan_source"
+	);
+	router.post("/api/source/:id/enable",
+		crawler::enable_source,
+		"enable_source"
+	);
+	router.post("/api/source/:id/disable",
+		crawler::disable_source,
+		"disable_source"
+	);
+	router.post("/api/source/:id/apply_folder_tags",
+		folder_tags::apply_folder_tags,
+		"apply_folder_tags"
+	);
+	router.post("/api/source/:id/apply_exif_tags",
+		exif_tags::apply_exif_tags,
+		"apply_exif_tags"
+	);
+	router.get("/api/tags/stats",
+		tags::tag_stats,
+		"tag_stats"
+	);
+	router.get("/api/tag_aliases",
+		tags::list_aliases,
+		"list_tag_aliases"
+	);
+	router.post("/api/tag_aliases",
+		tags::add_alias,
+		"add_tag_alias"
+	);
+	router.delete("/api/tag_aliases/:alias",
+		tags::delete_alias,
+		"delete_tag_alias"
+	);
+	router.post("/api/tags/merge",
+		tags::merge_tags,
+		"merge_tags"
+	);
+	router.post("/api/tags/rename",
+		tags::rename_tags,
+		"rename_tags"
+	);
+	router.get("/api/tags",
+		tags::list_tags,
+		"list_tags"
+	);
+	router.get("/api/photo/:id/tags",
+		tags::tags_of_photo,
+		"tags_of_photo"
+	);
+	router.post("/api/photo/:id/tags",
+		tags::add_tags_to_photo,
+		"add_tags_to_photo"
+	);
+	router.delete("/api/photo/:id/tags",
+		tags::remove_tags_from_photo,
+		"remove_tags_from_photo"
+	);
+	router.get("/api/photo/:id/marks",
+		ratings::get_marks,
+		"get_marks"
+	);
+	router.post("/api/photos/marks",
+		ratings::set_marks,
+		"set_marks"
+	);
+	router.get("/api/photo/:id/tag_suggestions",
+		tag_suggestions::tag_suggestions,
+		"tag_suggestions"
+	);
+	router.get("/api/trash",
+		trash::list_trash,
+		"list_trash"
+	);
+	router.post("/api/trash",
+		trash::trash,
+		"trash"
+	);
+	router.post("/api/trash/restore",
+		trash::restore,
+		"restore_from_trash"
+	);
+	router.post("/api/trash/purge",
+		trash::purge,
+		"purge_trash"
+	);
 	router.get("/api/healthcheck",
 		healthcheck::get_handler,
 		"healthcheck"
@@ -102,25 +341,57 @@ fn main() {
 		image::get,
 		"get_image"
 	);
+	router.post("/api/geotag",
+		geotag::geotag_photos,
+		"geotag"
+	);
+	router.get("/api/duplicates",
+		duplicates::list_duplicates,
+		"list_duplicates"
+	);
+	router.post("/api/duplicates/:hash/canonical",
+		duplicates::set_canonical,
+		"set_canonical"
+	);
+	router.get("/api/similar",
+		similar::list_clusters,
+		"list_similar_clusters"
+	);
+	router.get("/api/photo/:id/similar",
+		similar::find_similar,
+		"find_similar"
+	);
 
 	let mut chain = Chain::new(router);
 	let (logger_before, logger_after) = Logger::new(None);
 	chain.link_before(logger_before);
 	
+	chain.link_after(JsonErrors);
 	chain.link_after(logger_after);
 
 	// Initialize shared image processor pool
-	let image_processor_pool = ImageProcessorPool::new(settings.clone());
+	let image_processor_pool = ImageProcessorPool::new(settings.clone(), pool.clone());
+
+	// Initialize shared crawler pool
+	let crawler_pool = CrawlerPool::new(settings.clone(), pool.clone());
 
 	// Persistent data
 	chain.link_before(
 		State::<ImageProcessorPoolShared>::one(image_processor_pool)
 	);
 
+	chain.link_before(
+		State::<CrawlerPoolShared>::one(crawler_pool)
+	);
+
 	chain.link_before(
 		State::<Settings>::one(settings)
 	);
 
+	chain.link_before(
+		State::<DbPool>::one(pool)
+	);
+
 	let bind = "0.0.0.0:3000";
 	match Iron::new(chain).http(bind) {
 		Ok(_) => println!("Server bound to {:?}", bind),
@@ -0,0 +1,289 @@
+// Standard library includes
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::str;
+
+// Library includes
+use mysql as my;
+
+// Local includes
+use error::ApiError;
+
+/// Renditions served when `renditions` setting is missing
+const DEFAULT_RENDITIONS: &'static str = "medium";
+
+/// Rules of file system access
+///
+/// Renditions are served only from `gallery_folder` and only for sizes
+/// listed in `renditions` setting (comma-separated). Sources and their
+/// originals should be inside one of `source_roots` (comma-separated).
+/// Paths are canonicalized before the check, so neither ".." nor symlinks
+/// lead outside.
+#[derive(Debug, Clone)]
+pub struct PathPolicy {
+	gallery_folder: PathBuf,
+	renditions: Vec<String>,
+	source_roots: Vec<PathBuf>
+}
+
+impl PathPolicy {
+	pub fn from_settings(settings: &HashMap<String, String>) -> PathPolicy {
+		let list = |name: &str, default: &str| -> Vec<String> {
+			settings.get(name).map(|value| value.as_str()).unwrap_or(default)
+				.split(',')
+				.map(|item| item.trim().to_string())
+				.filter(|item| !item.is_empty())
+				.collect()
+		};
+
+		let gallery_folder = settings.get("gallery_folder")
+			.map(|folder| folder.as_str())
+			.unwrap_or("");
+
+		let source_roots = list("source_roots", "").into_iter()
+			.filter_map(|root| match fs::canonicalize(&root) {
+				Ok(root) => Some(root),
+				Err(err) => {
+					println!("Skipping source root {}: {:?}", root, err);
+					None
+				}
+			})
+			.collect();
+
+		PathPolicy {
+			gallery_folder: fs::canonicalize(gallery_folder)
+				.unwrap_or(PathBuf::from(gallery_folder)),
+			renditions: list("renditions", DEFAULT_RENDITIONS),
+			source_roots: source_roots
+		}
+	}
+
+	pub fn gallery_folder(&self) -> &Path {
+		&self.gallery_folder
+	}
+
+	/// Checks that `size` is one of configured renditions
+	pub fn is_rendition(&self, size: &str) -> bool {
+		self.renditions.iter().any(|rendition| rendition == size)
+	}
+
+	/// Resolves rendition file of the photo inside of the gallery folder
+	pub fn rendition(&self, size: &str, id: u64) -> Result<PathBuf, ApiError> {
+		if !self.is_rendition(size) {
+			return Err(ApiError::bad_request("unknown size"));
+		}
+
+		let path = self.gallery_folder.join(size).join(format!("{}.jpg", id));
+		let path = fs::canonicalize(&path)
+			.map_err(|_| ApiError::not_found("image of such size not found"))?;
+
+		if !path.starts_with(&self.gallery_folder) {
+			println!("Rendition {:?} leads outside of the gallery folder", path);
+			return Err(ApiError::forbidden("path is outside of the gallery folder"));
+		}
+		Ok(path)
+	}
+
+	/// Resolves directory of a new or relocated source
+	///
+	/// Directory should be inside of one of source roots.
+	pub fn source(&self, path: &str) -> Result<PathBuf, ApiError> {
+		if self.source_roots.is_empty() {
+			return Err(ApiError::forbidden("source_roots setting should be set"));
+		}
+
+		let path = fs::canonicalize(path)
+			.map_err(|_| ApiError::bad_request("path doesn't exist"))?;
+		if !path.is_dir() {
+			return Err(ApiError::bad_request("path should be a directory"));
+		}
+
+		self.confine_to_roots(path)
+	}
+
+	/// Resolves original file of the photo from its stored path
+	///
+	/// Original should be inside of one of source roots, symlinks pointing
+	/// outside are rejected.
+	pub fn original(&self, path: &str) -> Result<PathBuf, ApiError> {
+		let path = fs::canonicalize(decode_path(path))
+			.map_err(|_| ApiError::not_found("original file not found"))?;
+
+		self.confine_to_roots(path)
+	}
+
+	/// Resolves XMP sidecar of the photo from its stored path
+	///
+	/// Sidecar may not exist yet, its folder should be inside of one of
+	/// source roots then. Existing sidecar, including a dangling symlink,
+	/// is resolved as originals are.
+	pub fn sidecar(&self, path: &str) -> Result<PathBuf, ApiError> {
+		let path = decode_path(path);
+		if fs::symlink_metadata(&path).is_ok() {
+			let path = fs::canonicalize(&path)
+				.map_err(|_| ApiError::forbidden("sidecar is a dangling symlink"))?;
+			return self.confine_to_roots(path);
+		}
+
+		let (folder, name) = match (path.parent(), path.file_name()) {
+			(Some(folder), Some(name)) => (folder, name),
+			_ => return Err(ApiError::bad_request("invalid sidecar path"))
+		};
+		let folder = fs::canonicalize(folder)
+			.map_err(|_| ApiError::not_found("folder of the sidecar not found"))?;
+
+		Ok(self.confine_to_roots(folder)?.join(name))
+	}
+
+	/// Checks that the path, with symlinks resolved, is inside of one of
+	/// source roots
+	pub fn is_within_roots(&self, path: &Path) -> bool {
+		fs::canonicalize(path)
+			.map(|path| self.source_roots.iter().any(|root| path.starts_with(root)))
+			.unwrap_or(false)
+	}
+
+	fn confine_to_roots(&self, path: PathBuf) -> Result<PathBuf, ApiError> {
+		if self.source_roots.iter().any(|root| path.starts_with(root)) {
+			Ok(path)
+		} else {
+			println!("Path {:?} is outside of source roots", path);
+			Err(ApiError::forbidden("path is outside of allowed source roots"))
+		}
+	}
+}
+
+/// Falls back to folders of existing sources if `source_roots` isn't set
+///
+/// Without roots every file access is forbidden, which would break
+/// deployments upgrading from versions without the setting. The problem is
+/// reported at startup, new sources still require the setting to be placed
+/// outside of existing ones.
+pub fn default_source_roots(connection: &my::Pool, settings: &mut HashMap<String, String>) {
+	let is_set = settings.get("source_roots")
+		.map(|roots| !roots.trim().is_empty())
+		.unwrap_or(false);
+	if is_set {
+		return;
+	}
+
+	let mut roots: Vec<String> = vec![];
+	match connection.prep_exec(r"
+		SELECT `full_path` FROM `sources` WHERE `deleted_at` IS NULL", ()) {
+		Ok(result) => {
+			for row in result {
+				let full_path: String = match row {
+					Ok(row) => my::from_row(row),
+					Err(_) => continue
+				};
+				match decode_path(&full_path).to_str() {
+					// Settings list is comma-separated
+					Some(root) if !root.contains(',') => roots.push(root.to_string()),
+					_ => println!("Unable to use source {} as source root", full_path)
+				}
+			}
+		},
+		Err(err) => println!("{:?}", err)
+	}
+
+	println!("ERROR: source_roots setting isn't set in settings.toml, access to \
+		files is limited to existing sources: {:?}", roots);
+	settings.insert("source_roots".to_string(), roots.join(","));
+}
+
+/// Converts file system path to the form stored in the database
+///
+/// File names are arbitrary bytes while DB columns hold UTF-8, so bytes
+/// which aren't valid UTF-8 are stored as "%XX". "%" itself is stored as
+/// "%25" to keep the form reversible, everything else is kept as is.
+pub fn encode_path(path: &Path) -> String {
+	let mut bytes = path.as_os_str().as_bytes();
+	let mut encoded = String::with_capacity(bytes.len());
+
+	while !bytes.is_empty() {
+		let (valid, invalid) = match str::from_utf8(bytes) {
+			Ok(valid) => (valid, 0),
+			Err(err) => (
+				str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or(""),
+				err.error_len().unwrap_or(bytes.len() - err.valid_up_to())
+			)
+		};
+
+		encoded.push_str(&valid.replace('%', "%25"));
+		for byte in bytes[valid.len()..valid.len() + invalid].iter() {
+			encoded.push_str(&format!("%{:02X}", byte));
+		}
+		bytes = &bytes[valid.len() + invalid..];
+	}
+
+	encoded
+}
+
+/// Restores file system path from the form stored in the database
+///
+/// Reverse of `encode_path`. "%" not followed by two hex digits is kept
+/// literally.
+pub fn decode_path(stored: &str) -> PathBuf {
+	let bytes = stored.as_bytes();
+	let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+	let mut index = 0;
+
+	while index < bytes.len() {
+		let escaped = if bytes[index] == b'%' {
+			stored.get(index + 1..index + 3)
+				.filter(|hex| hex.bytes().all(|digit| digit.is_ascii_hexdigit()))
+				.and_then(|hex| u8::from_str_radix(hex, 16).ok())
+		} else {
+			None
+		};
+
+		match escaped {
+			Some(byte) => {
+				decoded.push(byte);
+				index += 3;
+			},
+			None => {
+				decoded.push(bytes[index]);
+				index += 1;
+			}
+		}
+	}
+
+	PathBuf::from(OsString::from_vec(decoded))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::ffi::OsStr;
+	use std::os::unix::ffi::OsStrExt;
+	use std::path::Path;
+
+	use super::{encode_path, decode_path};
+
+	#[test]
+	fn utf8_paths_are_kept_readable() {
+		assert_eq!(encode_path(Path::new("/фото/🎉 party/a.jpg")), "/фото/🎉 party/a.jpg");
+	}
+
+	#[test]
+	fn invalid_bytes_and_percent_are_escaped() {
+		let path = Path::new(OsStr::from_bytes(b"/a\xFF\xFEb/100%.jpg"));
+		assert_eq!(encode_path(path), "/a%FF%FEb/100%25.jpg");
+		assert_eq!(decode_path("/a%FF%FEb/100%25.jpg"), path);
+	}
+
+	#[test]
+	fn truncated_multibyte_sequence_roundtrips() {
+		let path = Path::new(OsStr::from_bytes(b"/photo\xD0"));
+		assert_eq!(encode_path(path), "/photo%D0");
+		assert_eq!(decode_path(&encode_path(path)), path);
+	}
+
+	#[test]
+	fn lone_percent_is_decoded_literally() {
+		assert_eq!(decode_path("/50%/a%zz.jpg"), Path::new("/50%/a%zz.jpg"));
+	}
+}
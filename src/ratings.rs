@@ -0,0 +1,137 @@
+// Standard library includes
+use std::str::FromStr;
+
+// Library includes
+use router::Router;
+use iron::prelude::*;
+use iron::status;
+use mysql as my;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+use search::{COLOR_LABELS, param_list, param_string};
+
+/// Culling marks of a photo
+#[derive(Serialize, Deserialize, Debug)]
+struct Marks {
+	id: u64,
+	/// 0-5 stars, 0 means not rated
+	rating: u8,
+	favorite: bool,
+	color_label: Option<String>
+}
+
+/// Provides rating, favorite flag and color label of the photo :id
+pub fn get_marks(request: &mut Request) -> IronResult<Response> {
+	let photo_id = request.extensions.get::<Router>().unwrap()
+		.find("id").unwrap_or("0")
+		.parse::<u64>().unwrap_or(0);
+
+	let connection = db::get_connection(request);
+	let result = connection.prep_exec(r"
+		SELECT `rating`, `favorite`, `color_label` FROM `photos`
+		WHERE `id` = :id",
+		params!{"id" => photo_id});
+
+	match result {
+		Ok(mut result) => {
+			match result.next() {
+				Some(Ok(row)) => {
+					let (rating, favorite, color_label) = my::from_row(row);
+					let marks = Marks {
+						id: photo_id,
+						rating: rating,
+						favorite: favorite,
+						color_label: color_label
+					};
+					Ok(
+						Response::with(
+							(status::Ok, to_string_pretty(&marks).unwrap())
+						)
+					)
+				},
+				_ => Err(ApiError::not_found("photo not found").into())
+			}
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Sets rating, favorite flag and/or color label of several photos at once
+///
+/// Accepts `photo_ids` as comma-separated list or array and any of
+/// `rating` (0-5), `favorite` (true or false) and `color_label` (red,
+/// yellow, green, blue, purple or none to remove the label). Marks which
+/// aren't passed stay as they are.
+pub fn set_marks(request: &mut Request) -> IronResult<Response> {
+	let params = request_params(request)?;
+	let photo_ids = param_list(&params, "photo_ids")
+		.iter()
+		.filter_map(|id| u64::from_str(id).ok())
+		.map(|id| id.to_string())
+		.collect::<Vec<String>>();
+
+	if photo_ids.is_empty() {
+		return Err(ApiError::bad_request("photo_ids should be set").into());
+	}
+
+	let mut assignments: Vec<&'static str> = vec![];
+	let mut values: Vec<(String, my::Value)> = vec![];
+
+	if let Some(rating) = param_string(&params, "rating") {
+		match u8::from_str(&rating) {
+			Ok(rating) if rating <= 5 => {
+				assignments.push("`rating` = :rating");
+				values.push(("rating".to_string(), rating.into()));
+			},
+			_ => return Err(ApiError::bad_request("rating should be 0-5").into())
+		}
+	}
+	if let Some(favorite) = param_string(&params, "favorite") {
+		assignments.push("`favorite` = :favorite");
+		values.push(("favorite".to_string(),
+			(favorite == "true" || favorite == "1").into()));
+	}
+	if let Some(color_label) = param_string(&params, "color_label") {
+		let color_label = color_label.to_lowercase();
+		if color_label == "none" || color_label.is_empty() {
+			assignments.push("`color_label` = NULL");
+		} else if COLOR_LABELS.contains(&color_label.as_str()) {
+			assignments.push("`color_label` = :color_label");
+			values.push(("color_label".to_string(), color_label.into()));
+		} else {
+			let message = format!("color_label should be one of {}, none",
+				COLOR_LABELS.join(", "));
+			return Err(ApiError::bad_request(&message).into());
+		}
+	}
+
+	if assignments.is_empty() {
+		return Err(ApiError::bad_request("rating, favorite or color_label should be set").into());
+	}
+
+	values.push(("photo_ids".to_string(), photo_ids.join(",").into()));
+
+	let connection = db::get_connection(request);
+	let result = connection.prep_exec(
+		format!("UPDATE `photos` SET {} WHERE FIND_IN_SET(`id`, :photo_ids)",
+			assignments.join(", ")),
+		my::Params::from(values));
+
+	match result {
+		Ok(result) => {
+			let out_json = json!({
+				"status": "ok",
+				"updated": result.affected_rows()
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
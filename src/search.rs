@@ -0,0 +1,395 @@
+// Standard library includes
+use std::str::FromStr;
+
+// Library includes
+use iron::prelude::*;
+use iron::status;
+use params::{Map, Value, FromValue};
+use mysql as my;
+use chrono::NaiveDate;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+
+/// Upper limit for page size
+const MAX_PER_PAGE: u64 = 1000;
+
+/// Color labels photo can be marked with, same as in Lightroom
+pub const COLOR_LABELS: [&'static str; 5] = ["red", "yellow", "green", "blue", "purple"];
+
+/// Photo search criteria
+///
+/// Used by /api/search directly and stored as JSON by smart albums.
+/// All criteria are optional and combined with AND.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct PhotoQuery {
+	/// Photo should have all of these tags
+	#[serde(default)]
+	pub tags: Vec<String>,
+	/// Capture date range, inclusive, "YYYY-MM-DD"
+	pub date_from: Option<String>,
+	pub date_to: Option<String>,
+	pub source_id: Option<u64>,
+	/// Part of camera name, like "EOS 80D"
+	pub camera: Option<String>,
+	pub has_gps: Option<bool>,
+	/// Bounding box of photo location
+	pub lat_min: Option<f64>,
+	pub lat_max: Option<f64>,
+	pub lon_min: Option<f64>,
+	pub lon_max: Option<f64>,
+	/// Star rating range, inclusive, 0-5
+	pub rating_min: Option<u8>,
+	pub rating_max: Option<u8>,
+	pub favorite: Option<bool>,
+	/// One of COLOR_LABELS, "none" matches photos without label
+	pub color_label: Option<String>,
+	/// Restricts results to a single photo. Used to check if the photo
+	/// matches the query, never stored.
+	#[serde(skip)]
+	pub photo_id: Option<u64>
+}
+
+/// Page of results requested by the client
+///
+/// Without `per_page` all results are returned at once.
+#[derive(Debug, Clone, Copy)]
+pub struct Pagination {
+	pub page: u64,
+	pub per_page: Option<u64>
+}
+
+impl PhotoQuery {
+	/// Reads search criteria from request parameters
+	///
+	/// Returns error message if some parameter is malformed.
+	pub fn from_params(params: &Map) -> Result<PhotoQuery, String> {
+		let mut query = PhotoQuery::default();
+
+		query.tags = param_list(params, "tags");
+		query.date_from = param_date(params, "date_from")?;
+		query.date_to = param_date(params, "date_to")?;
+		query.source_id = param_number(params, "source_id")?;
+		query.camera = param_string(params, "camera")
+			.filter(|camera| !camera.is_empty());
+		query.has_gps = match param_string(params, "has_gps") {
+			Some(value) => Some(value == "true" || value == "1"),
+			None => None
+		};
+		query.lat_min = param_number(params, "lat_min")?;
+		query.lat_max = param_number(params, "lat_max")?;
+		query.lon_min = param_number(params, "lon_min")?;
+		query.lon_max = param_number(params, "lon_max")?;
+		query.rating_min = param_number(params, "rating_min")?;
+		query.rating_max = param_number(params, "rating_max")?;
+		query.favorite = match param_string(params, "favorite") {
+			Some(value) => Some(value == "true" || value == "1"),
+			None => None
+		};
+		query.color_label = match param_string(params, "color_label") {
+			Some(ref value) if value.is_empty() => None,
+			Some(value) => {
+				let value = value.to_lowercase();
+				if value != "none" && !COLOR_LABELS.contains(&value.as_str()) {
+					return Err(format!("color_label should be one of {}, none",
+						COLOR_LABELS.join(", ")));
+				}
+				Some(value)
+			},
+			None => None
+		};
+
+		Ok(query)
+	}
+
+	/// Checks if request contains any search criteria
+	pub fn is_present_in(params: &Map) -> bool {
+		[
+			"tags", "date_from", "date_to", "source_id", "camera", "has_gps",
+			"lat_min", "lat_max", "lon_min", "lon_max", "rating_min",
+			"rating_max", "favorite", "color_label"
+		].iter().any(|name| params.find(&[name]).is_some())
+	}
+
+	/// Builds WHERE conditions over `photos` table with named parameters
+	pub fn conditions(&self) -> (String, Vec<(String, my::Value)>) {
+		// Duplicates of a canonical copy, photos in trash and photos of
+		// disabled sources are never shown
+		let mut conditions: Vec<String> = vec![
+			"photos.duplicate_of IS NULL".to_string(),
+			visible("photos")
+		];
+		let mut values: Vec<(String, my::Value)> = vec![];
+
+		for (index, tag) in self.tags.iter().enumerate() {
+			// Tag may be given by its name or by one of its aliases
+			let name = format!("tag_{}", index);
+			let alias = format!("tag_alias_{}", index);
+			conditions.push(format!(
+				"EXISTS (SELECT 1 FROM `photo_tags`, `tags` \
+				WHERE photo_tags.photo = photos.id AND \
+				tags.id = photo_tags.tag AND (tags.name = :{} OR tags.id IN \
+				(SELECT `tag` FROM `tag_aliases` WHERE `alias` = :{})))",
+				name, alias));
+			values.push((name, tag.clone().into()));
+			values.push((alias, tag.clone().into()));
+		}
+
+		if let Some(ref date_from) = self.date_from {
+			conditions.push("photos.exif_datetime >= :date_from".to_string());
+			values.push(("date_from".to_string(), date_from.clone().into()));
+		}
+		if let Some(ref date_to) = self.date_to {
+			conditions.push(
+				"photos.exif_datetime < DATE_ADD(:date_to, INTERVAL 1 DAY)"
+				.to_string());
+			values.push(("date_to".to_string(), date_to.clone().into()));
+		}
+		if let Some(photo_id) = self.photo_id {
+			conditions.push("photos.id = :photo_id".to_string());
+			values.push(("photo_id".to_string(), photo_id.into()));
+		}
+		if let Some(source_id) = self.source_id {
+			conditions.push("photos.source = :source_id".to_string());
+			values.push(("source_id".to_string(), source_id.into()));
+		}
+		if let Some(ref camera) = self.camera {
+			conditions.push(
+				"photos.exif_camera LIKE CONCAT('%', :camera, '%')".to_string());
+			values.push(("camera".to_string(), camera.clone().into()));
+		}
+		match self.has_gps {
+			Some(true) => conditions.push(
+				"(IFNULL(photos.exif_latitude, 0) <> 0 OR \
+				IFNULL(photos.exif_longitude, 0) <> 0)".to_string()),
+			Some(false) => conditions.push(
+				"(IFNULL(photos.exif_latitude, 0) = 0 AND \
+				IFNULL(photos.exif_longitude, 0) = 0)".to_string()),
+			None => {}
+		}
+		if let Some(rating_min) = self.rating_min {
+			conditions.push("photos.rating >= :rating_min".to_string());
+			values.push(("rating_min".to_string(), rating_min.into()));
+		}
+		if let Some(rating_max) = self.rating_max {
+			conditions.push("photos.rating <= :rating_max".to_string());
+			values.push(("rating_max".to_string(), rating_max.into()));
+		}
+		if let Some(favorite) = self.favorite {
+			conditions.push("photos.favorite = :favorite".to_string());
+			values.push(("favorite".to_string(), favorite.into()));
+		}
+		match self.color_label.as_ref().map(|label| label.as_str()) {
+			Some("none") => conditions.push("photos.color_label IS NULL".to_string()),
+			Some(color_label) => {
+				conditions.push("photos.color_label = :color_label".to_string());
+				values.push(("color_label".to_string(), color_label.into()));
+			},
+			None => {}
+		}
+
+		let bounds = [
+			("lat_min", "photos.exif_latitude >=", self.lat_min),
+			("lat_max", "photos.exif_latitude <=", self.lat_max),
+			("lon_min", "photos.exif_longitude >=", self.lon_min),
+			("lon_max", "photos.exif_longitude <=", self.lon_max)
+		];
+		for &(name, condition, value) in bounds.iter() {
+			if let Some(value) = value {
+				conditions.push(format!("{} :{}", condition, name));
+				values.push((name.to_string(), value.into()));
+			}
+		}
+
+		(conditions.join(" AND "), values)
+	}
+}
+
+impl Pagination {
+	/// Reads `page` (starting from 1) and `per_page` request parameters
+	pub fn from_params(params: &Map) -> Pagination {
+		let page = param_number::<u64>(params, "page")
+			.unwrap_or(None)
+			.unwrap_or(1)
+			.max(1);
+		let per_page = param_number::<u64>(params, "per_page")
+			.unwrap_or(None)
+			.map(|per_page| per_page.max(1).min(MAX_PER_PAGE));
+
+		Pagination { page: page, per_page: per_page }
+	}
+
+	/// Returns requested page of already loaded ids
+	pub fn apply(&self, ids: &[u64]) -> Vec<u64> {
+		match self.per_page {
+			Some(per_page) => {
+				ids.iter()
+					.skip(((self.page - 1) * per_page) as usize)
+					.take(per_page as usize)
+					.cloned()
+					.collect()
+			},
+			None => ids.to_vec()
+		}
+	}
+}
+
+/// Condition hiding photos in trash and photos of disabled sources
+///
+/// Photo is in trash if it was trashed itself or its source was. `table`
+/// is name or alias of `photos` table in the query.
+pub fn visible(table: &str) -> String {
+	format!("{0}.deleted_at IS NULL AND {0}.source NOT IN \
+		(SELECT `id` FROM `sources` WHERE `deleted_at` IS NOT NULL OR NOT `enabled`)",
+		table)
+}
+
+/// Keeps only visible photos of `ids` in their order
+pub fn visible_ids(connection: &my::Pool, ids: &[u64]) -> Result<Vec<u64>, my::Error> {
+	let ids_list: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+
+	let mut found: Vec<u64> = vec![];
+	for row in connection.prep_exec(format!(r"
+		SELECT photos.id FROM `photos`
+		WHERE FIND_IN_SET(photos.id, :ids) AND {}",
+		visible("photos")),
+		params!{"ids" => ids_list.join(",")})? {
+		found.push(my::from_row(row?));
+	}
+
+	Ok(ids.iter().filter(|id| found.contains(id)).cloned().collect())
+}
+
+/// Searches photos by criteria
+///
+/// Returns requested page of photo ids and total number of found photos.
+pub fn find_photos(connection: &my::Pool, query: &PhotoQuery, pagination: &Pagination)
+	-> Result<(Vec<u64>, u64), my::Error> {
+	let (conditions, values) = query.conditions();
+	let values = if values.is_empty() {
+		my::Params::Empty
+	} else {
+		my::Params::from(values)
+	};
+
+	let mut total: u64 = 0;
+	for row in connection.prep_exec(
+		format!("SELECT COUNT(*) FROM `photos` WHERE {}", conditions),
+		values.clone())? {
+		total = my::from_row(row?);
+	}
+
+	let limit = match pagination.per_page {
+		Some(per_page) => {
+			format!(" LIMIT {}, {}", (pagination.page - 1) * per_page, per_page)
+		},
+		None => String::new()
+	};
+
+	let mut ids: Vec<u64> = vec![];
+	for row in connection.prep_exec(
+		format!("SELECT photos.id FROM `photos` WHERE {} ORDER BY photos.id{}",
+			conditions, limit),
+		values)? {
+		ids.push(my::from_row(row?));
+	}
+
+	Ok((ids, total))
+}
+
+/// Builds response in the common photo listing format
+pub fn photos_response(ids: Vec<u64>, total: u64, pagination: &Pagination)
+	-> Response {
+	let out_json = json!({
+		"photos": ids,
+		"total": total,
+		"page": pagination.page,
+		"per_page": pagination.per_page,
+	});
+
+	Response::with(
+		(status::Ok, to_string_pretty(&out_json).unwrap())
+	)
+}
+
+/// Searches photos by criteria passed as request parameters
+///
+/// Accepts `tags` (list), `date_from`, `date_to`, `source_id`, `camera`,
+/// `has_gps`, `lat_min`, `lat_max`, `lon_min`, `lon_max`, `rating_min`,
+/// `rating_max`, `favorite`, `color_label` as well as `page` and
+/// `per_page`.
+pub fn search(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+
+	let query = match PhotoQuery::from_params(&params) {
+		Ok(query) => query,
+		Err(message) => return Err(ApiError::bad_request(&message).into())
+	};
+	let pagination = Pagination::from_params(&params);
+
+	match find_photos(&connection, &query, &pagination) {
+		Ok((ids, total)) => Ok(photos_response(ids, total, &pagination)),
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Reads optional string parameter
+pub fn param_string(params: &Map, name: &str) -> Option<String> {
+	params.find(&[name]).and_then(|value| String::from_value(value))
+}
+
+/// Reads list passed either as array or as comma-separated string
+///
+/// Empty items are skipped.
+pub fn param_list(params: &Map, name: &str) -> Vec<String> {
+	let items: Vec<String> = match params.find(&[name]) {
+		Some(&Value::Array(ref values)) => {
+			values.iter()
+				.filter_map(|value| String::from_value(value))
+				.collect()
+		},
+		Some(value) => {
+			String::from_value(value)
+				.unwrap_or(String::new())
+				.split(',')
+				.map(|item| item.to_string())
+				.collect()
+		},
+		None => vec![]
+	};
+
+	items.into_iter()
+		.map(|item| item.trim().to_string())
+		.filter(|item| !item.is_empty())
+		.collect()
+}
+
+/// Reads optional numeric parameter. Malformed number is an error.
+pub fn param_number<T: FromStr>(params: &Map, name: &str)
+	-> Result<Option<T>, String> {
+	match param_string(params, name) {
+		Some(ref value) if value.is_empty() => Ok(None),
+		Some(value) => {
+			T::from_str(&value)
+				.map(Some)
+				.map_err(|_| format!("{} should be a number", name))
+		},
+		None => Ok(None)
+	}
+}
+
+/// Reads optional date parameter in "YYYY-MM-DD" format
+fn param_date(params: &Map, name: &str) -> Result<Option<String>, String> {
+	match param_string(params, name) {
+		Some(ref value) if value.is_empty() => Ok(None),
+		Some(value) => {
+			NaiveDate::parse_from_str(&value, "%Y-%m-%d")
+				.map(|date| Some(date.format("%Y-%m-%d").to_string()))
+				.map_err(|_| format!("{} should be a date like 2023-04-16", name))
+		},
+		None => Ok(None)
+	}
+}
@@ -0,0 +1,471 @@
+// Standard library includes
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::str::FromStr;
+
+// Library includes
+use router::Router;
+use iron::prelude::*;
+use iron::status;
+use iron::mime;
+use persistent::State;
+use params::Map;
+use mysql as my;
+use chrono::{NaiveDate, NaiveDateTime};
+use sha2::{Sha256, Digest};
+use hmac::Hmac;
+use pbkdf2::pbkdf2;
+use serde_json;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+use albums;
+use crawler;
+use image;
+use paths::PathPolicy;
+use search::{PhotoQuery, Pagination, find_photos, photos_response, visible_ids,
+	param_string, param_list};
+use Settings;
+
+/// Name of the size which stands for the original file
+const ORIGINAL_SIZE: &'static str = "original";
+
+/// Header carrying the password of a protected link
+const PASSWORD_HEADER: &'static str = "X-Share-Password";
+
+/// PBKDF2 iterations for new password hashes
+const PASSWORD_ITERATIONS: u32 = 100000;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Share {
+	id: u64,
+	token: String,
+	album: Option<u64>,
+	query: Option<PhotoQuery>,
+	photo_ids: Option<Vec<u64>>,
+	expires_at: Option<String>,
+	has_password: bool,
+	allow_originals: bool,
+	#[serde(skip)]
+	password_hash: Option<String>,
+	#[serde(skip)]
+	password_salt: Option<String>,
+	/// None for legacy salted SHA-256 hashes
+	#[serde(skip)]
+	password_iterations: Option<u32>
+}
+
+/// Creates public share link
+///
+/// What is shared is defined by one of:
+/// * `album_id` - album
+/// * `photo_ids` - explicit list of photos
+/// * search criteria accepted by /api/search - live tag query
+///
+/// Optional `expires_at` ("YYYY-MM-DD" or "YYYY-MM-DD HH:MM:SS"),
+/// `password` and `allow_originals` restrict the link.
+pub fn create_share(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+
+	let album_id = param_string(&params, "album_id")
+		.and_then(|value| u64::from_str(&value).ok());
+	let photo_ids = param_list(&params, "photo_ids");
+	let query = if PhotoQuery::is_present_in(&params) {
+		match PhotoQuery::from_params(&params) {
+			Ok(query) => Some(serde_json::to_string(&query).unwrap()),
+			Err(message) => return Err(ApiError::bad_request(&message).into())
+		}
+	} else {
+		None
+	};
+
+	let targets = album_id.iter().count() + query.iter().count() +
+		if photo_ids.is_empty() { 0 } else { 1 };
+	if targets != 1 {
+		return Err(
+			ApiError::bad_request(
+				"exactly one of album_id, photo_ids or search query should be set"
+			).into()
+		);
+	}
+
+	let photo_ids: Vec<u64> = photo_ids.iter()
+		.filter_map(|id| u64::from_str(id).ok())
+		.collect();
+	let photo_ids = if photo_ids.is_empty() {
+		None
+	} else {
+		Some(photo_ids.iter()
+			.map(|id| id.to_string())
+			.collect::<Vec<String>>()
+			.join(","))
+	};
+
+	let expires_at = match parse_expiry(&params) {
+		Ok(expires_at) => expires_at,
+		Err(message) => return Err(ApiError::bad_request(&message).into())
+	};
+
+	let (password_hash, password_salt) = match param_string(&params, "password") {
+		Some(ref password) if !password.is_empty() => {
+			let salt = random_hex(16).map_err(ApiError::from)?;
+			(Some(hash_password(password, &salt, PASSWORD_ITERATIONS)), Some(salt))
+		},
+		_ => (None, None)
+	};
+	let password_iterations = password_hash.as_ref().map(|_| PASSWORD_ITERATIONS);
+
+	let allow_originals = param_string(&params, "allow_originals")
+		.map(|value| value == "true" || value == "1")
+		.unwrap_or(false);
+
+	let token = random_hex(32).map_err(ApiError::from)?;
+
+	let result = connection.prep_exec(r"
+		INSERT INTO `shares`
+		        (`token`, `album`, `query`, `photo_ids`, `expires_at`,
+		         `password_hash`, `password_salt`, `password_iterations`,
+		         `allow_originals`)
+		VALUES  (:token, :album, :query, :photo_ids, :expires_at,
+		         :password_hash, :password_salt, :password_iterations,
+		         :allow_originals)",
+		params!{
+			"token" => &token,
+			"album" => album_id,
+			"query" => query,
+			"photo_ids" => photo_ids,
+			"expires_at" => expires_at,
+			"password_hash" => password_hash,
+			"password_salt" => password_salt,
+			"password_iterations" => password_iterations,
+			"allow_originals" => allow_originals
+		}
+	);
+
+	match result {
+		Ok(result) => {
+			let out_json = json!({
+				"status": "ok",
+				"id": result.last_insert_id(),
+				"token": token
+			});
+			Ok(
+				Response::with(
+					(status::Created, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Provides all share links including expired ones
+pub fn list_shares(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+	match get_shares(&connection, None) {
+		Ok(shares) => {
+			let out_json = json!({
+				"shares": shares,
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Revokes share link :token
+pub fn delete_share(request: &mut Request) -> IronResult<Response> {
+	let token = token(request);
+	let connection = db::get_connection(request);
+
+	let result = connection.prep_exec(r"
+		DELETE FROM `shares` WHERE `token` = :token",
+		params!{"token" => token});
+
+	match result {
+		Ok(ref result) if result.affected_rows() == 0 => {
+			Err(ApiError::not_found("share not found").into())
+		},
+		Ok(_) => {
+			let out_json = json!({
+				"status": "ok"
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Public listing of shared photos
+///
+/// Works with the token only. Requires the password in `X-Share-Password`
+/// header if the link is protected. Accepts `page` and `per_page` like other photo listings.
+pub fn list_shared_photos(request: &mut Request) -> IronResult<Response> {
+	let token = token(request);
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+	let pagination = Pagination::from_params(&params);
+
+	let share = open_share(&connection, &token, &share_password(request))?;
+
+	let result = match share.query {
+		Some(ref query) => find_photos(&connection, query, &pagination),
+		None => {
+			shared_photo_ids(&connection, &share).map(|ids| {
+				let total = ids.len() as u64;
+				(pagination.apply(&ids), total)
+			})
+		}
+	};
+
+	match result {
+		Ok((ids, total)) => Ok(photos_response(ids, total, &pagination)),
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Public access to renditions of shared photos
+///
+/// Same as /api/image/:id/:size, but only for photos included in the share.
+/// Size "original" serves the original file if the link allows it.
+pub fn get_shared_image(request: &mut Request) -> IronResult<Response> {
+	let token = token(request);
+	let connection = db::get_connection(request);
+	let (photo_id, size) = {
+		let router = request.extensions.get::<Router>().unwrap();
+		(
+			router.find("id").unwrap_or("0").parse::<u64>().unwrap_or(0),
+			router.find("size").unwrap_or("").to_string()
+		)
+	};
+	let policy = {
+		let rwlock = request.get::<State<Settings>>().unwrap();
+		let settings = rwlock.read().unwrap();
+		PathPolicy::from_settings(&settings)
+	};
+
+	if size != ORIGINAL_SIZE && !policy.is_rendition(&size) {
+		return Err(ApiError::bad_request("unknown size").into());
+	}
+
+	let share = open_share(&connection, &token, &share_password(request))?;
+
+	let included = match share.query {
+		Some(ref query) => {
+			let query = PhotoQuery {
+				photo_id: Some(photo_id),
+				..query.clone()
+			};
+			let pagination = Pagination { page: 1, per_page: Some(1) };
+			find_photos(&connection, &query, &pagination).map(|(_, total)| total > 0)
+		},
+		None => shared_photo_ids(&connection, &share).map(|ids| ids.contains(&photo_id))
+	};
+
+	match included {
+		Ok(true) => {},
+		Ok(false) => return Err(ApiError::not_found("photo not found").into()),
+		Err(err) => return Err(ApiError::from(err).into())
+	}
+
+	let data = if size == ORIGINAL_SIZE {
+		if !share.allow_originals {
+			return Err(ApiError::forbidden("originals aren't shared").into());
+		}
+		let path = match crawler::get_photo_path(&connection, photo_id) {
+			Some(path) => policy.original(&path)?,
+			None => return Err(ApiError::not_found("photo not found").into())
+		};
+		let mut buffer: Vec<u8> = vec![];
+		File::open(path)
+			.and_then(|mut file| file.read_to_end(&mut buffer))
+			.map_err(ApiError::from)?;
+		buffer
+	} else {
+		image::read_image(&policy, photo_id, &size)?
+	};
+
+	let content_type = "image/jpeg".parse::<mime::Mime>().unwrap();
+	Ok(Response::with((content_type, status::Ok, data)))
+}
+
+/// Finds valid share by token and checks the password
+///
+/// Fails if the link doesn't exist, expired or the password is wrong.
+fn open_share(connection: &my::Pool, token: &str, password: &str) -> Result<Share, ApiError> {
+	let share = match get_shares(connection, Some(token))?.pop() {
+		Some(share) => share,
+		None => return Err(ApiError::not_found("share not found"))
+	};
+
+	if let Some(ref expires_at) = share.expires_at {
+		let expired = NaiveDateTime::parse_from_str(expires_at, "%Y-%m-%d %H:%M:%S")
+			.map(|expires_at| expires_at <= ::chrono::Local::now().naive_local())
+			.unwrap_or(true);
+		if expired {
+			return Err(ApiError::new(status::Gone, "expired", "share link expired"));
+		}
+	}
+
+	if let (&Some(ref hash), &Some(ref salt)) =
+		(&share.password_hash, &share.password_salt) {
+		let expected = match share.password_iterations {
+			Some(iterations) => hash_password(password, salt, iterations),
+			None => legacy_hash_password(password, salt)
+		};
+		if !constant_time_eq(expected.as_bytes(), hash.as_bytes()) {
+			return Err(ApiError::new(status::Unauthorized, "unauthorized",
+				"password required"));
+		}
+
+		if share.password_iterations.is_none() {
+			let result = connection.prep_exec(r"
+				UPDATE `shares`
+				SET `password_hash` = :password_hash,
+				    `password_iterations` = :password_iterations
+				WHERE `id` = :id",
+				params!{
+					"id" => share.id,
+					"password_hash" => hash_password(password, salt, PASSWORD_ITERATIONS),
+					"password_iterations" => PASSWORD_ITERATIONS
+				});
+			if let Err(err) = result {
+				println!("Unable to rehash password of share {}: {:?}", share.id, err);
+			}
+		}
+	}
+
+	Ok(share)
+}
+
+/// Returns ids of photos in album or explicit list shares, photos in
+/// trash or in disabled sources are left out
+fn shared_photo_ids(connection: &my::Pool, share: &Share) -> Result<Vec<u64>, my::Error> {
+	match (share.album, &share.photo_ids) {
+		(Some(album_id), _) => albums::get_album_photos(connection, album_id),
+		(None, &Some(ref ids)) => visible_ids(connection, ids),
+		(None, &None) => Ok(vec![])
+	}
+}
+
+/// Loads one share by token or all shares
+fn get_shares(connection: &my::Pool, token: Option<&str>) -> Result<Vec<Share>, my::Error> {
+	let mut shares: Vec<Share> = vec![];
+
+	for row in connection.prep_exec(r"
+		SELECT `id`, `token`, `album`, `query`, `photo_ids`,
+		DATE_FORMAT(`expires_at`, '%Y-%m-%d %H:%i:%s'),
+		`password_hash`, `password_salt`, `password_iterations`, `allow_originals`
+		FROM `shares`
+		WHERE :all OR `token` = :token
+		ORDER BY `id`",
+		params!{
+			"all" => token.is_none(),
+			"token" => token.unwrap_or("")
+		})? {
+		let (id, token, album, query, photo_ids, expires_at, password_hash,
+			password_salt, password_iterations, allow_originals): (u64, String,
+			Option<u64>, Option<String>, Option<String>, Option<String>,
+			Option<String>, Option<String>, Option<u32>, bool) = my::from_row(row?);
+
+		shares.push(Share {
+			id: id,
+			token: token,
+			album: album,
+			query: query.and_then(|query| serde_json::from_str(&query).ok()),
+			photo_ids: photo_ids.map(|ids| {
+				ids.split(',')
+					.filter_map(|id| u64::from_str(id).ok())
+					.collect()
+			}),
+			expires_at: expires_at,
+			has_password: password_hash.is_some(),
+			allow_originals: allow_originals,
+			password_hash: password_hash,
+			password_salt: password_salt,
+			password_iterations: password_iterations
+		});
+	}
+
+	Ok(shares)
+}
+
+/// Reads `expires_at` parameter as MySQL DATETIME
+///
+/// Date without time means the end of that day.
+fn parse_expiry(params: &Map) -> Result<Option<String>, String> {
+	let value = match param_string(params, "expires_at") {
+		Some(ref value) if value.is_empty() => return Ok(None),
+		Some(value) => value,
+		None => return Ok(None)
+	};
+
+	if let Ok(datetime) = NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S") {
+		return Ok(Some(datetime.format("%Y-%m-%d %H:%M:%S").to_string()));
+	}
+	match NaiveDate::parse_from_str(&value, "%Y-%m-%d") {
+		Ok(date) => Ok(Some(date.format("%Y-%m-%d 23:59:59").to_string())),
+		Err(_) => Err("expires_at should be like 2023-04-16 or \
+			2023-04-16 07:20:03".to_string())
+	}
+}
+
+/// Generates random hex string from `bytes` random bytes
+fn random_hex(bytes: usize) -> io::Result<String> {
+	let mut buffer = vec![0u8; bytes];
+	File::open("/dev/urandom")
+		.and_then(|mut random| random.read_exact(&mut buffer))?;
+
+	Ok(buffer.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// PBKDF2-HMAC-SHA256 of the password, hex-encoded
+fn hash_password(password: &str, salt: &str, iterations: u32) -> String {
+	let mut hash = [0u8; 32];
+	pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt.as_bytes(), iterations as usize,
+		&mut hash);
+	hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Salted SHA-256 of the password used before PBKDF2, hex-encoded
+fn legacy_hash_password(password: &str, salt: &str) -> String {
+	let mut hasher = Sha256::new();
+	hasher.input(salt.as_bytes());
+	hasher.input(password.as_bytes());
+	format!("{:x}", hasher.result())
+}
+
+/// Compares two byte strings without leaking position of the difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+	a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Reads password of a protected link from `X-Share-Password` header
+fn share_password(request: &Request) -> String {
+	request.headers.get_raw(PASSWORD_HEADER)
+		.and_then(|values| values.first())
+		.map(|value| String::from_utf8_lossy(value).into_owned())
+		.unwrap_or(String::new())
+}
+
+/// Reads :token url segment
+fn token(request: &Request) -> String {
+	request.extensions.get::<Router>().unwrap()
+		.find("token").unwrap_or("")
+		.to_string()
+}
@@ -0,0 +1,254 @@
+// Standard library includes
+use std::collections::HashMap;
+
+// Library includes
+use router::Router;
+use iron::prelude::*;
+use iron::status;
+use mysql as my;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+use search::{visible, param_number};
+
+/// Default maximum Hamming distance between hashes of similar photos
+const DEFAULT_THRESHOLD: u32 = 10;
+
+/// Hashes have 64 bits, larger thresholds match everything anyway
+const MAX_THRESHOLD: u32 = 64;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SimilarPhoto {
+	id: u64,
+	distance: u32
+}
+
+/// BK-tree over 64-bit hashes with Hamming distance as metric
+///
+/// Allows to find all hashes within given distance without comparing
+/// every pair of photos.
+struct BkTree {
+	nodes: Vec<BkNode>
+}
+
+struct BkNode {
+	id: u64,
+	hash: u64,
+	children: HashMap<u32, usize>
+}
+
+impl BkTree {
+	fn new() -> BkTree {
+		BkTree { nodes: vec![] }
+	}
+
+	fn insert(&mut self, id: u64, hash: u64) {
+		let new_index = self.nodes.len();
+		self.nodes.push(BkNode { id: id, hash: hash, children: HashMap::new() });
+
+		if new_index == 0 {
+			return;
+		}
+
+		let mut index = 0;
+		loop {
+			let distance = hamming(self.nodes[index].hash, hash);
+			match self.nodes[index].children.get(&distance).cloned() {
+				Some(child) => index = child,
+				None => {
+					self.nodes[index].children.insert(distance, new_index);
+					return;
+				}
+			}
+		}
+	}
+
+	/// Returns ids and distances of all hashes within `threshold` of `hash`
+	fn find(&self, hash: u64, threshold: u32) -> Vec<(u64, u32)> {
+		let mut found = vec![];
+		if self.nodes.is_empty() {
+			return found;
+		}
+
+		let mut stack = vec![0];
+		while let Some(index) = stack.pop() {
+			let node = &self.nodes[index];
+			let distance = hamming(node.hash, hash);
+			if distance <= threshold {
+				found.push((node.id, distance));
+			}
+			for (child_distance, child) in node.children.iter() {
+				if *child_distance + threshold >= distance &&
+					*child_distance <= distance + threshold {
+					stack.push(*child);
+				}
+			}
+		}
+		found
+	}
+}
+
+/// Number of differing bits of two hashes
+fn hamming(a: u64, b: u64) -> u32 {
+	(a ^ b).count_ones()
+}
+
+/// Provides clusters of visually similar photos
+///
+/// Accepts optional `threshold` (maximum Hamming distance, 10 by default)
+/// and `source_id` to limit search to one source. Photos are joined into
+/// a cluster transitively, so bursts end up in a single cluster.
+pub fn list_clusters(request: &mut Request) -> IronResult<Response> {
+	let params = request_params(request)?;
+	let threshold = param_number::<u32>(&params, "threshold")
+		.map_err(|message| ApiError::bad_request(&message))?
+		.unwrap_or(DEFAULT_THRESHOLD)
+		.min(MAX_THRESHOLD);
+	let source_id = param_number::<u64>(&params, "source_id")
+		.map_err(|message| ApiError::bad_request(&message))?
+		.unwrap_or(0);
+
+	let connection = db::get_connection(request);
+	let hashes = get_hashes(&connection, source_id);
+
+	let mut tree = BkTree::new();
+	for &(id, hash) in hashes.iter() {
+		tree.insert(id, hash);
+	}
+
+	// Union-find over photo ids
+	let mut parents: HashMap<u64, u64> = HashMap::new();
+	for &(id, hash) in hashes.iter() {
+		for (other, _) in tree.find(hash, threshold) {
+			let root = find_root(&mut parents, id);
+			let other_root = find_root(&mut parents, other);
+			if root != other_root {
+				parents.insert(other_root, root);
+			}
+		}
+	}
+
+	let mut clusters: HashMap<u64, Vec<u64>> = HashMap::new();
+	for &(id, _) in hashes.iter() {
+		let root = find_root(&mut parents, id);
+		clusters.entry(root).or_insert(vec![]).push(id);
+	}
+
+	let mut clusters: Vec<Vec<u64>> = clusters.into_iter()
+		.map(|(_, mut ids)| { ids.sort(); ids })
+		.filter(|ids| ids.len() > 1)
+		.collect();
+	clusters.sort_by_key(|ids| ids[0]);
+
+	let out_json = json!({
+		"threshold": threshold,
+		"clusters": clusters,
+	});
+
+	Ok(
+		Response::with(
+			(status::Ok, to_string_pretty(&out_json).unwrap())
+		)
+	)
+}
+
+/// Provides photos visually similar to the photo :id
+///
+/// Results are ordered by distance, closest first.
+pub fn find_similar(request: &mut Request) -> IronResult<Response> {
+	let id = request.extensions.get::<Router>().unwrap()
+		.find("id").unwrap_or("0")
+		.parse::<u64>().unwrap_or(0);
+
+	let params = request_params(request)?;
+	let threshold = param_number::<u32>(&params, "threshold")
+		.map_err(|message| ApiError::bad_request(&message))?
+		.unwrap_or(DEFAULT_THRESHOLD)
+		.min(MAX_THRESHOLD);
+
+	let connection = db::get_connection(request);
+	let result = connection.prep_exec(format!(r"
+		SELECT other.id, BIT_COUNT(other.phash ^ photo.phash) AS distance
+		FROM `photos` AS photo, `photos` AS other
+		WHERE photo.id = :id AND other.id <> photo.id AND
+		photo.phash IS NOT NULL AND other.phash IS NOT NULL AND
+		other.duplicate_of IS NULL AND {} AND
+		BIT_COUNT(other.phash ^ photo.phash) <= :threshold
+		ORDER BY distance, other.id", visible("other")),
+		params!{"id" => id, "threshold" => threshold}
+	);
+
+	match result {
+		Ok(result) => {
+			let mut photos: Vec<SimilarPhoto> = vec![];
+			result.for_each(|row| {
+				match row {
+					Ok(row) => {
+						let (id, distance) = my::from_row(row);
+						photos.push(SimilarPhoto { id: id, distance: distance });
+					},
+					Err(_) => {}
+				}
+			});
+
+			let out_json = json!({
+				"id": id,
+				"threshold": threshold,
+				"photos": photos,
+			});
+
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Loads perceptual hashes of all visible photos (or of one source)
+fn get_hashes(connection: &my::Pool, source_id: u64) -> Vec<(u64, u64)> {
+	let result = connection.prep_exec(format!(r"
+		SELECT `id`, `phash` FROM `photos`
+		WHERE `phash` IS NOT NULL AND `duplicate_of` IS NULL AND {} AND
+		(:source_id = 0 OR `source` = :source)
+		ORDER BY `id`", visible("photos")),
+		params!{"source_id" => source_id, "source" => source_id}
+	);
+
+	let mut hashes: Vec<(u64, u64)> = vec![];
+	match result {
+		Ok(result) => {
+			result.for_each(|row| {
+				match row {
+					Ok(row) => hashes.push(my::from_row(row)),
+					Err(_) => {}
+				}
+			});
+		},
+		Err(err) => println!("{:?}", err)
+	}
+	hashes
+}
+
+/// Finds cluster representative with path compression
+fn find_root(parents: &mut HashMap<u64, u64>, id: u64) -> u64 {
+	let mut root = id;
+	while let Some(&parent) = parents.get(&root) {
+		if parent == root {
+			break;
+		}
+		root = parent;
+	}
+
+	let mut current = id;
+	while current != root {
+		let next = *parents.get(&current).unwrap_or(&root);
+		parents.insert(current, root);
+		current = next;
+	}
+	root
+}
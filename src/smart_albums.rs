@@ -0,0 +1,227 @@
+// Library includes
+use router::Router;
+use iron::prelude::*;
+use iron::status;
+use mysql as my;
+use serde_json;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+use search::{PhotoQuery, Pagination, find_photos, photos_response, param_string};
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SmartAlbum {
+	id: u64,
+	title: String,
+	query: PhotoQuery
+}
+
+/// Provides all smart albums with their queries
+pub fn list_smart_albums(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+	let out_json = json!({
+		"smart_albums": get_smart_albums(&connection, 0),
+	});
+
+	Ok(
+		Response::with(
+			(status::Ok, to_string_pretty(&out_json).unwrap())
+		)
+	)
+}
+
+/// Provides smart album :id
+pub fn get_smart_album(request: &mut Request) -> IronResult<Response> {
+	let album_id = album_id(request);
+	let connection = db::get_connection(request);
+
+	match get_smart_albums(&connection, album_id).pop() {
+		Some(album) => {
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&album).unwrap())
+				)
+			)
+		},
+		None => Err(ApiError::not_found("smart album not found").into())
+	}
+}
+
+/// Saves named search query as a smart album
+///
+/// Accepts `title` and the same criteria as /api/search.
+pub fn create_smart_album(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+
+	let title = param_string(&params, "title").unwrap_or(String::new());
+	if title.is_empty() {
+		return Err(ApiError::bad_request("title should be set").into());
+	}
+	let query = match PhotoQuery::from_params(&params) {
+		Ok(query) => query,
+		Err(message) => return Err(ApiError::bad_request(&message).into())
+	};
+
+	let result = connection.prep_exec(r"
+		INSERT INTO `smart_albums` (`title`, `query`)
+		VALUES (:title, :query)",
+		params!{
+			"title" => title,
+			"query" => serde_json::to_string(&query).unwrap()
+		}
+	);
+
+	match result {
+		Ok(result) => {
+			let out_json = json!({
+				"status": "ok",
+				"id": result.last_insert_id()
+			});
+			Ok(
+				Response::with(
+					(status::Created, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Changes title and/or query of the smart album
+///
+/// If any search criteria are passed, the whole query is replaced.
+pub fn update_smart_album(request: &mut Request) -> IronResult<Response> {
+	let album_id = album_id(request);
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+
+	let title = param_string(&params, "title").filter(|title| !title.is_empty());
+	let query = if PhotoQuery::is_present_in(&params) {
+		match PhotoQuery::from_params(&params) {
+			Ok(query) => Some(serde_json::to_string(&query).unwrap()),
+			Err(message) => return Err(ApiError::bad_request(&message).into())
+		}
+	} else {
+		None
+	};
+
+	if get_smart_albums(&connection, album_id).is_empty() {
+		return Err(ApiError::not_found("smart album not found").into());
+	}
+
+	let result = connection.prep_exec(r"
+		UPDATE `smart_albums`
+		SET   `title` = IFNULL(:title, `title`),
+		      `query` = IFNULL(:query, `query`)
+		WHERE `id` = :id",
+		params!{
+			"id" => album_id,
+			"title" => title,
+			"query" => query
+		}
+	);
+
+	match result {
+		Ok(_) => {
+			let out_json = json!({
+				"status": "ok"
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Deletes smart album
+pub fn delete_smart_album(request: &mut Request) -> IronResult<Response> {
+	let album_id = album_id(request);
+	let connection = db::get_connection(request);
+
+	let result = connection.prep_exec(r"
+		DELETE FROM `smart_albums` WHERE `id` = :id",
+		params!{"id" => album_id});
+
+	match result {
+		Ok(ref result) if result.affected_rows() == 0 => {
+			Err(ApiError::not_found("smart album not found").into())
+		},
+		Ok(_) => {
+			let out_json = json!({
+				"status": "ok"
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Provides photos matching the smart album query at the moment
+///
+/// Response has the same format and pagination as /api/list_photos.
+pub fn list_smart_album_photos(request: &mut Request) -> IronResult<Response> {
+	let album_id = album_id(request);
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+	let pagination = Pagination::from_params(&params);
+
+	let album = match get_smart_albums(&connection, album_id).pop() {
+		Some(album) => album,
+		None => return Err(ApiError::not_found("smart album not found").into())
+	};
+
+	match find_photos(&connection, &album.query, &pagination) {
+		Ok((ids, total)) => Ok(photos_response(ids, total, &pagination)),
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Loads smart albums from DB. Album id = 0 loads all of them.
+fn get_smart_albums(connection: &my::Pool, album_id: u64) -> Vec<SmartAlbum> {
+	let result = connection.prep_exec(r"
+		SELECT `id`, `title`, `query` FROM `smart_albums`
+		WHERE :album_id = 0 OR `id` = :id
+		ORDER BY `id`",
+		params!{"album_id" => album_id, "id" => album_id}
+	);
+
+	let mut albums: Vec<SmartAlbum> = vec![];
+	match result {
+		Ok(result) => {
+			result.for_each(|row| {
+				match row {
+					Ok(row) => {
+						let (id, title, query): (u64, String, String) =
+							my::from_row(row);
+						albums.push(SmartAlbum {
+							id: id,
+							title: title,
+							query: serde_json::from_str(&query)
+								.unwrap_or_default()
+						});
+					},
+					Err(_) => {}
+				}
+			});
+		},
+		Err(err) => println!("{:?}", err)
+	}
+	albums
+}
+
+/// Reads :id url segment
+fn album_id(request: &Request) -> u64 {
+	request.extensions.get::<Router>().unwrap()
+		.find("id").unwrap_or("0")
+		.parse::<u64>().unwrap_or(0)
+}
@@ -0,0 +1,202 @@
+// Standard library includes
+use std::collections::HashMap;
+
+// Library includes
+use router::Router;
+use iron::prelude::*;
+use iron::status;
+use mysql as my;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+use search::{visible, param_number};
+use tags;
+
+/// Default time window around the photo, in minutes
+const DEFAULT_WINDOW: u64 = 180;
+/// Default radius around the photo location, in meters
+const DEFAULT_RADIUS: f64 = 500.0;
+/// Default maximum Hamming distance between perceptual hashes
+const DEFAULT_THRESHOLD: u32 = 10;
+/// Default number of suggestions
+const DEFAULT_LIMIT: usize = 20;
+
+/// Length of a degree of latitude, in meters
+const METERS_PER_DEGREE: f64 = 111320.0;
+
+/// Reasons for a photo to be considered related, in response order
+const REASONS: [&'static str; 4] = ["time", "location", "folder", "similar"];
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Suggestion {
+	name: String,
+	/// Number of related photos having the tag
+	count: u64,
+	/// Why those photos were considered related
+	reasons: Vec<&'static str>
+}
+
+/// Suggests tags for the photo :id
+///
+/// Looks at photos taken within `window` minutes (default 180), within
+/// `radius` meters (default 500), in the same folder, or with perceptual
+/// hash within `threshold` bits (default 10). Tags of those photos are
+/// ranked by the number of related photos having them. Tags already
+/// assigned to the photo are not suggested. Returns at most `limit`
+/// suggestions (default 20).
+pub fn tag_suggestions(request: &mut Request) -> IronResult<Response> {
+	let photo_id = request.extensions.get::<Router>().unwrap()
+		.find("id").unwrap_or("0")
+		.parse::<u64>().unwrap_or(0);
+
+	let connection = db::get_connection(request);
+	let params = request_params(request)?;
+	let window = param_number(&params, "window")
+		.map_err(|message| ApiError::bad_request(&message))?
+		.unwrap_or(DEFAULT_WINDOW);
+	let radius = param_number(&params, "radius")
+		.map_err(|message| ApiError::bad_request(&message))?
+		.unwrap_or(DEFAULT_RADIUS);
+	let threshold = param_number(&params, "threshold")
+		.map_err(|message| ApiError::bad_request(&message))?
+		.unwrap_or(DEFAULT_THRESHOLD);
+	let limit = param_number(&params, "limit")
+		.map_err(|message| ApiError::bad_request(&message))?
+		.unwrap_or(DEFAULT_LIMIT);
+
+	let related = match find_related(&connection, photo_id, window, radius, threshold) {
+		Ok(related) => related,
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	match rank_tags(&connection, photo_id, &related) {
+		Ok(mut suggestions) => {
+			suggestions.truncate(limit);
+
+			let out_json = json!({
+				"id": photo_id,
+				"suggestions": suggestions,
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Finds photos related to photo_id
+///
+/// Returns map from photo id to flags matching REASONS.
+///
+/// Candidates are narrowed by time window and by a bounding box around the
+/// location on indexed columns, exact distance is calculated only for
+/// photos inside of the box.
+fn find_related(connection: &my::Pool, photo_id: u64, window: u64, radius: f64,
+	threshold: u32) -> Result<HashMap<u64, [bool; 4]>, my::Error> {
+	let mut related: HashMap<u64, [bool; 4]> = HashMap::new();
+	let degrees = radius / METERS_PER_DEGREE;
+
+	// Longitude degrees get shorter towards the poles, so the box is wider
+	// there. Folder is the relative path without the file name.
+	let in_box = r"
+		IFNULL(photo.exif_latitude, 0) <> 0 AND
+		other.exif_latitude BETWEEN photo.exif_latitude - :degrees
+			AND photo.exif_latitude + :degrees AND
+		other.exif_longitude BETWEEN
+			photo.exif_longitude - :degrees / GREATEST(COS(RADIANS(photo.exif_latitude)), 0.01)
+			AND photo.exif_longitude + :degrees / GREATEST(COS(RADIANS(photo.exif_latitude)), 0.01)";
+	let in_window = r"
+		other.exif_datetime BETWEEN photo.exif_datetime - INTERVAL :window MINUTE
+			AND photo.exif_datetime + INTERVAL :window MINUTE";
+	for row in connection.prep_exec(format!(r"
+		SELECT other.id,
+		IFNULL({in_window}, 0) AS by_time,
+		IFNULL({in_box} AND
+			IFNULL(other.exif_latitude, 0) <> 0 AND
+			ST_Distance_Sphere(
+				POINT(photo.exif_longitude, photo.exif_latitude),
+				POINT(other.exif_longitude, other.exif_latitude)
+			) <= :radius, 0) AS by_location,
+		other.source = photo.source AND
+			SUBSTRING(other.relative_path, 1, LENGTH(other.relative_path) -
+				LENGTH(SUBSTRING_INDEX(other.relative_path, '/', -1))) =
+			SUBSTRING(photo.relative_path, 1, LENGTH(photo.relative_path) -
+				LENGTH(SUBSTRING_INDEX(photo.relative_path, '/', -1)))
+			AS by_folder,
+		IFNULL(BIT_COUNT(other.phash ^ photo.phash) <= :threshold, 0) AS by_phash
+		FROM `photos` AS photo, `photos` AS other
+		WHERE photo.id = :id AND other.id <> photo.id AND
+		other.duplicate_of IS NULL AND {visible} AND
+		(({in_window}) OR ({in_box}) OR other.source = photo.source OR
+			BIT_COUNT(other.phash ^ photo.phash) <= :threshold)
+		HAVING by_time OR by_location OR by_folder OR by_phash",
+		in_window = in_window, in_box = in_box, visible = visible("other")),
+		params!{
+			"window" => window,
+			"degrees" => degrees,
+			"radius" => radius,
+			"threshold" => threshold,
+			"id" => photo_id
+		})? {
+		let (id, by_time, by_location, by_folder, by_phash):
+			(u64, bool, bool, bool, bool) = my::from_row(row?);
+		related.insert(id, [by_time, by_location, by_folder, by_phash]);
+	}
+
+	Ok(related)
+}
+
+/// Ranks tags of related photos by number of photos having them
+fn rank_tags(connection: &my::Pool, photo_id: u64, related: &HashMap<u64, [bool; 4]>)
+	-> Result<Vec<Suggestion>, my::Error> {
+	if related.is_empty() {
+		return Ok(vec![]);
+	}
+
+	let assigned = tags::get_photo_tags(connection, photo_id)?
+		.into_iter()
+		.map(|tag| tag.to_lowercase())
+		.collect::<Vec<String>>();
+	let ids = related.keys()
+		.map(|id| id.to_string())
+		.collect::<Vec<String>>()
+		.join(",");
+
+	let mut ranked: HashMap<String, (u64, [bool; 4])> = HashMap::new();
+	for row in connection.prep_exec(r"
+		SELECT photo_tags.photo, tags.name FROM `photo_tags`, `tags`
+		WHERE tags.id = photo_tags.tag AND FIND_IN_SET(photo_tags.photo, :ids)",
+		params!{"ids" => ids})? {
+		let (id, name): (u64, String) = my::from_row(row?);
+		if assigned.contains(&name.to_lowercase()) {
+			continue;
+		}
+
+		let reasons = related[&id];
+		let entry = ranked.entry(name).or_insert((0, [false; 4]));
+		entry.0 += 1;
+		for index in 0..REASONS.len() {
+			entry.1[index] |= reasons[index];
+		}
+	}
+
+	let mut suggestions: Vec<Suggestion> = ranked.into_iter()
+		.map(|(name, (count, reasons))| Suggestion {
+			name: name,
+			count: count,
+			reasons: REASONS.iter()
+				.zip(reasons.iter())
+				.filter(|&(_, &flag)| flag)
+				.map(|(&reason, _)| reason)
+				.collect()
+		})
+		.collect();
+	suggestions.sort_by(|a, b| b.count.cmp(&a.count).then(a.name.cmp(&b.name)));
+
+	Ok(suggestions)
+}
@@ -0,0 +1,711 @@
+// Standard library includes
+use std::str::FromStr;
+
+// Library includes
+use router::Router;
+use iron::prelude::*;
+use iron::status;
+use mysql as my;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+use search::{PhotoQuery, param_list, param_string};
+
+/// Number of tags returned by statistics unless asked otherwise
+const DEFAULT_STATS_LIMIT: u64 = 100;
+
+/// Tag assigned by user
+pub const ORIGIN_MANUAL: &'static str = "manual";
+/// Tag imported from keywords in XMP sidecar or embedded metadata
+pub const ORIGIN_IMPORT: &'static str = "import";
+/// Tag generated from names of folders containing the photo
+pub const ORIGIN_FOLDER: &'static str = "folder";
+/// Tag generated from EXIF attributes by rules from settings
+pub const ORIGIN_EXIF: &'static str = "exif";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Tag {
+	id: u64,
+	name: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TagAlias {
+	alias: String,
+	tag: String
+}
+
+/// Usage of a tag among some set of photos
+#[derive(Serialize, Deserialize, Debug)]
+struct TagStats {
+	name: String,
+	/// Number of photos with the tag
+	count: u64,
+	/// When the tag was assigned for the first and last time
+	first_used: Option<String>,
+	last_used: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PhotoTag {
+	name: String,
+	origin: String
+}
+
+/// Provides all known tags
+pub fn list_tags(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+	let result = connection.prep_exec(r"
+		SELECT `id`, `name` FROM `tags` ORDER BY `name`", ());
+
+	match result {
+		Ok(result) => {
+			let mut tags: Vec<Tag> = vec![];
+			result.for_each(|row| {
+				match row {
+					Ok(row) => {
+						let (id, name) = my::from_row(row);
+						tags.push(Tag { id: id, name: name });
+					},
+					Err(_) => {}
+				}
+			});
+
+			let out_json = json!({
+				"tags": tags,
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Provides usage statistics of tags on photos matching search criteria
+///
+/// Accepts the same criteria as /api/search and `limit` (default 100).
+/// Without criteria it describes the whole library, which is what a tag
+/// cloud needs. Tags from `tags` criterion are left out, so with
+/// `tags=beach` the result lists tags most often used together with
+/// "beach", i.e. "refine by" facets of the current search. Tags are
+/// ordered by number of photos.
+pub fn tag_stats(request: &mut Request) -> IronResult<Response> {
+	let params = request_params(request)?;
+
+	let query = match PhotoQuery::from_params(&params) {
+		Ok(query) => query,
+		Err(message) => return Err(ApiError::bad_request(&message).into())
+	};
+	let limit = param_string(&params, "limit")
+		.and_then(|value| u64::from_str(&value).ok())
+		.unwrap_or(DEFAULT_STATS_LIMIT)
+		.max(1);
+
+	let connection = db::get_connection(request);
+	match find_tag_stats(&connection, &query, limit) {
+		Ok((total, tags)) => {
+			let out_json = json!({
+				"total": total,
+				"tags": tags,
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Counts tags on photos matching the query
+///
+/// Returns number of matching photos and at most `limit` tags.
+fn find_tag_stats(connection: &my::Pool, query: &PhotoQuery, limit: u64)
+	-> Result<(u64, Vec<TagStats>), my::Error> {
+	// Tags of the query itself are on every found photo, skip them
+	let mut excluded: Vec<String> = vec![];
+	for tag in query.tags.iter() {
+		if let Some(tag_id) = resolve_tag(connection, tag)? {
+			excluded.push(tag_id.to_string());
+		}
+	}
+
+	let (conditions, mut values) = query.conditions();
+
+	let count_values = if values.is_empty() {
+		my::Params::Empty
+	} else {
+		my::Params::from(values.clone())
+	};
+
+	let mut total: u64 = 0;
+	for row in connection.prep_exec(
+		format!("SELECT COUNT(*) FROM `photos` WHERE {}", conditions),
+		count_values)? {
+		total = my::from_row(row?);
+	}
+
+	values.push(("excluded".to_string(), excluded.join(",").into()));
+
+	let mut tags: Vec<TagStats> = vec![];
+	for row in connection.prep_exec(format!(r"
+		SELECT tags.name, COUNT(*),
+		DATE_FORMAT(MIN(photo_tags.created_at), '%Y-%m-%d %H:%i:%s'),
+		DATE_FORMAT(MAX(photo_tags.created_at), '%Y-%m-%d %H:%i:%s')
+		FROM `photos`
+		JOIN `photo_tags` ON photo_tags.photo = photos.id
+		JOIN `tags` ON tags.id = photo_tags.tag
+		WHERE {} AND NOT FIND_IN_SET(tags.id, :excluded)
+		GROUP BY tags.id, tags.name
+		ORDER BY COUNT(*) DESC, tags.name
+		LIMIT {}", conditions, limit),
+		my::Params::from(values))? {
+		let (name, count, first_used, last_used) = my::from_row(row?);
+		tags.push(TagStats {
+			name: name,
+			count: count,
+			first_used: first_used,
+			last_used: last_used
+		});
+	}
+
+	Ok((total, tags))
+}
+
+/// Provides tags assigned to the photo :id
+///
+/// Every tag comes with its origin, so automatic tags can be told apart
+/// from manual ones.
+pub fn tags_of_photo(request: &mut Request) -> IronResult<Response> {
+	let photo_id = photo_id(request);
+
+	let connection = db::get_connection(request);
+	let result = connection.prep_exec(r"
+		SELECT tags.name, photo_tags.origin FROM `tags`, `photo_tags`
+		WHERE tags.id = photo_tags.tag AND photo_tags.photo = :photo
+		ORDER BY tags.name",
+		params!{"photo" => photo_id});
+
+	match result {
+		Ok(result) => {
+			let mut tags: Vec<PhotoTag> = vec![];
+			result.for_each(|row| {
+				match row {
+					Ok(row) => {
+						let (name, origin) = my::from_row(row);
+						tags.push(PhotoTag { name: name, origin: origin });
+					},
+					Err(_) => {}
+				}
+			});
+
+
+			let out_json = json!({
+				"id": photo_id,
+				"tags": tags,
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Adds tags to the photo :id
+///
+/// Accepts `tags` as comma-separated list or array. Unknown tags are
+/// created.
+pub fn add_tags_to_photo(request: &mut Request) -> IronResult<Response> {
+	let photo_id = photo_id(request);
+	let params = request_params(request)?;
+	let tags = param_list(&params, "tags");
+
+	let connection = db::get_connection(request);
+	match assign_tags(&connection, photo_id, &tags, ORIGIN_MANUAL) {
+		Ok(_) => {
+			let out_json = json!({
+				"status": "ok"
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Removes tags from the photo :id
+pub fn remove_tags_from_photo(request: &mut Request) -> IronResult<Response> {
+	let photo_id = photo_id(request);
+	let params = request_params(request)?;
+	let tags = param_list(&params, "tags");
+
+	let connection = db::get_connection(request);
+	let result = connection.prep_exec(r"
+		DELETE photo_tags FROM `photo_tags`, `tags`
+		WHERE photo_tags.photo = :photo AND tags.id = photo_tags.tag AND
+		(FIND_IN_SET(tags.name, :tags) OR tags.id IN
+		(SELECT `tag` FROM `tag_aliases` WHERE FIND_IN_SET(`alias`, :aliases)))",
+		params!{
+			"photo" => photo_id,
+			"tags" => tags.join(","),
+			"aliases" => tags.join(",")
+		}
+	);
+
+	match result {
+		Ok(_) => {
+			let out_json = json!({
+				"status": "ok"
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Assigns tags to the photo creating missing tags
+///
+/// `origin` tells where assignment came from (see ORIGIN_* constants).
+/// Aliases are resolved to their canonical tags. Existing assignments keep
+/// their origin. Returns number of new assignments.
+pub fn assign_tags(connection: &my::Pool, photo_id: u64, tags: &[String], origin: &str)
+	-> Result<u64, my::Error> {
+	let mut assigned: u64 = 0;
+
+	for tag in tags.iter() {
+		let tag_id = match resolve_tag(connection, tag)? {
+			Some(tag_id) => tag_id,
+			None => {
+				connection.prep_exec(r"
+					INSERT INTO `tags` (`name`) VALUES (:name)",
+					params!{"name" => tag})?.last_insert_id()
+			}
+		};
+
+		let result = connection.prep_exec(r"
+			INSERT IGNORE INTO `photo_tags` (`photo`, `tag`, `origin`)
+			VALUES (:photo, :tag, :origin)",
+			params!{"photo" => photo_id, "tag" => tag_id, "origin" => origin})?;
+		assigned += result.affected_rows();
+	}
+
+	Ok(assigned)
+}
+
+/// Finds id of the tag by its name or alias, case-insensitively
+pub fn resolve_tag(connection: &my::Pool, name: &str) -> Result<Option<u64>, my::Error> {
+	let mut result = connection.prep_exec(r"
+		SELECT `id` FROM `tags` WHERE `name` = :name
+		UNION ALL
+		SELECT `tag` FROM `tag_aliases` WHERE `alias` = :alias
+		LIMIT 1",
+		params!{"name" => name, "alias" => name})?;
+
+	match result.next() {
+		Some(row) => Ok(Some(my::from_row(row?))),
+		None => Ok(None)
+	}
+}
+
+/// Provides all aliases with names of tags they resolve to
+pub fn list_aliases(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+	let result = connection.prep_exec(r"
+		SELECT tag_aliases.alias, tags.name FROM `tag_aliases`, `tags`
+		WHERE tags.id = tag_aliases.tag
+		ORDER BY tag_aliases.alias", ());
+
+	match result {
+		Ok(result) => {
+			let mut aliases: Vec<TagAlias> = vec![];
+			result.for_each(|row| {
+				match row {
+					Ok(row) => {
+						let (alias, tag) = my::from_row(row);
+						aliases.push(TagAlias { alias: alias, tag: tag });
+					},
+					Err(_) => {}
+				}
+			});
+
+			let out_json = json!({
+				"aliases": aliases,
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Makes `alias` resolve to existing tag `tag` in search and tagging
+///
+/// Alias can't be a name of existing tag, such tags should be merged.
+pub fn add_alias(request: &mut Request) -> IronResult<Response> {
+	let params = request_params(request)?;
+	let alias = param_string(&params, "alias").unwrap_or(String::new());
+	let tag = param_string(&params, "tag").unwrap_or(String::new());
+	let (alias, tag) = (alias.trim(), tag.trim());
+
+	if alias.is_empty() || tag.is_empty() {
+		return Err(ApiError::bad_request("alias and tag should be set").into());
+	}
+
+	let connection = db::get_connection(request);
+
+	let tag_id = match resolve_tag(&connection, tag) {
+		Ok(Some(tag_id)) => tag_id,
+		Ok(None) => return Err(ApiError::not_found("tag not found").into()),
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	let existing = connection.prep_exec(r"
+		SELECT `id` FROM `tags` WHERE `name` = :name",
+		params!{"name" => alias})
+		.map(|mut result| result.next().is_some());
+	match existing {
+		Ok(true) => {
+			return Err(ApiError::conflict("tag with such name exists, merge it instead").into());
+		},
+		Ok(false) => {},
+		Err(err) => return Err(ApiError::from(err).into())
+	}
+
+	let result = connection.prep_exec(r"
+		INSERT INTO `tag_aliases` (`alias`, `tag`) VALUES (:alias, :tag)
+		ON DUPLICATE KEY UPDATE `tag` = VALUES(`tag`)",
+		params!{"alias" => alias, "tag" => tag_id});
+
+	match result {
+		Ok(_) => {
+			let out_json = json!({
+				"status": "ok"
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Removes alias :alias
+pub fn delete_alias(request: &mut Request) -> IronResult<Response> {
+	let alias = request.extensions.get::<Router>().unwrap()
+		.find("alias").unwrap_or("")
+		.to_string();
+
+	let connection = db::get_connection(request);
+	let result = connection.prep_exec(r"
+		DELETE FROM `tag_aliases` WHERE `alias` = :alias",
+		params!{"alias" => alias});
+
+	match result {
+		Ok(ref result) if result.affected_rows() == 0 => {
+			Err(ApiError::not_found("alias not found").into())
+		},
+		Ok(_) => {
+			let out_json = json!({
+				"status": "ok"
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Merges tags `from` (list) into tag `into`
+///
+/// All photo assignments and aliases are moved atomically and merged tags
+/// are deleted. With `keep_alias` = true old names become aliases of
+/// `into`. Tag `into` is created if it doesn't exist.
+pub fn merge_tags(request: &mut Request) -> IronResult<Response> {
+	let params = request_params(request)?;
+	let from = param_list(&params, "from");
+	let into = param_string(&params, "into").unwrap_or(String::new());
+	let into = into.trim();
+	let keep_alias = param_string(&params, "keep_alias")
+		.map(|value| value == "true" || value == "1")
+		.unwrap_or(false);
+
+	if from.is_empty() || into.is_empty() {
+		return Err(ApiError::bad_request("from and into should be set").into());
+	}
+
+	let connection = db::get_connection(request);
+
+	let into_id = match resolve_tag(&connection, into) {
+		Ok(Some(tag_id)) => tag_id,
+		Ok(None) => {
+			match connection.prep_exec(r"
+				INSERT INTO `tags` (`name`) VALUES (:name)",
+				params!{"name" => into}) {
+				Ok(result) => result.last_insert_id(),
+				Err(err) => return Err(ApiError::from(err).into())
+			}
+		},
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	let mut from_ids: Vec<u64> = vec![];
+	for name in from.iter() {
+		match resolve_tag(&connection, name) {
+			Ok(Some(tag_id)) => {
+				if tag_id != into_id && !from_ids.contains(&tag_id) {
+					from_ids.push(tag_id);
+				}
+			},
+			Ok(None) => {
+				return Err(ApiError::not_found(&format!("tag {} not found", name)).into());
+			},
+			Err(err) => return Err(ApiError::from(err).into())
+		}
+	}
+
+	let mut transaction = match connection.start_transaction(false, None, None) {
+		Ok(transaction) => transaction,
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	for from_id in from_ids.iter() {
+		if let Err(err) = merge_into(&mut transaction, *from_id, into_id, keep_alias) {
+			return Err(ApiError::from(err).into());
+		}
+	}
+
+	match transaction.commit() {
+		Ok(_) => {
+			let out_json = json!({
+				"status": "ok",
+				"merged": from_ids.len()
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Renames tag `from` to `to`
+///
+/// With `prefix` = true all hierarchical tags under `from` are renamed
+/// too, so "place/Italy" -> "places/Italy" also turns "place/Italy/Rome"
+/// into "places/Italy/Rome". If the new name is taken by another tag, the
+/// tags are merged. Everything happens in one transaction.
+pub fn rename_tags(request: &mut Request) -> IronResult<Response> {
+	let params = request_params(request)?;
+	let from = param_string(&params, "from").unwrap_or(String::new());
+	let to = param_string(&params, "to").unwrap_or(String::new());
+	let (from, to) = (from.trim().to_string(), to.trim().to_string());
+	let prefix = param_string(&params, "prefix")
+		.map(|value| value == "true" || value == "1")
+		.unwrap_or(false);
+
+	if from.is_empty() || to.is_empty() {
+		return Err(ApiError::bad_request("from and to should be set").into());
+	}
+
+	let connection = db::get_connection(request);
+	let mut transaction = match connection.start_transaction(false, None, None) {
+		Ok(transaction) => transaction,
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	match rename_in_transaction(&mut transaction, &from, &to, prefix) {
+		Ok(0) => Err(ApiError::not_found("tag not found").into()),
+		Ok(renamed) => {
+			match transaction.commit() {
+				Ok(_) => {
+					let out_json = json!({
+						"status": "ok",
+						"renamed": renamed
+					});
+					Ok(
+						Response::with(
+							(status::Ok, to_string_pretty(&out_json).unwrap())
+						)
+					)
+				},
+				Err(err) => Err(ApiError::from(err).into())
+			}
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Renames tags inside of transaction, returns number of affected tags
+fn rename_in_transaction(transaction: &mut my::Transaction, from: &str, to: &str,
+	prefix: bool) -> Result<u64, my::Error> {
+	// Escape LIKE wildcards in the prefix
+	let pattern = format!("{}/%", from
+		.replace('\\', "\\\\")
+		.replace('%', "\\%")
+		.replace('_', "\\_"));
+
+	let mut tags: Vec<(u64, String)> = vec![];
+	for row in transaction.prep_exec(r"
+		SELECT `id`, `name` FROM `tags`
+		WHERE `name` = :name OR (:prefix AND `name` LIKE :pattern)
+		FOR UPDATE",
+		params!{"name" => from, "prefix" => prefix, "pattern" => pattern})? {
+		tags.push(my::from_row(row?));
+	}
+
+	for &(id, ref name) in tags.iter() {
+		let new_name = renamed_tag(name, from, to);
+
+		let mut existing: Option<u64> = None;
+		for row in transaction.prep_exec(r"
+			SELECT `id` FROM `tags` WHERE `name` = :name AND `id` <> :id",
+			params!{"name" => &new_name, "id" => id})? {
+			existing = Some(my::from_row(row?));
+		}
+
+		match existing {
+			Some(into_id) => merge_into(transaction, id, into_id, false)?,
+			None => {
+				transaction.prep_exec(r"
+					DELETE FROM `tag_aliases` WHERE `alias` = :name",
+					params!{"name" => &new_name})?;
+				transaction.prep_exec(r"
+					UPDATE `tags` SET `name` = :name WHERE `id` = :id",
+					params!{"name" => &new_name, "id" => id})?;
+			}
+		}
+	}
+
+	Ok(tags.len() as u64)
+}
+
+/// Replaces leading levels of hierarchical tag `name` matched by `from`
+/// with `to`, the rest of the name is kept as is
+///
+/// `name` matched `from` by DB collation, which ignores case and accents,
+/// so the levels are counted rather than bytes.
+fn renamed_tag(name: &str, from: &str, to: &str) -> String {
+	let levels = from.split('/').count();
+	let mut new_name = to.to_string();
+	for level in name.split('/').skip(levels) {
+		new_name.push('/');
+		new_name.push_str(level);
+	}
+	new_name
+}
+
+/// Moves assignments and aliases of tag `from_id` to `into_id` and
+/// deletes `from_id`
+fn merge_into(transaction: &mut my::Transaction, from_id: u64, into_id: u64,
+	keep_alias: bool) -> Result<(), my::Error> {
+	transaction.prep_exec(r"
+		INSERT IGNORE INTO `photo_tags` (`photo`, `tag`, `created_at`, `origin`)
+		SELECT `photo`, :into, `created_at`, `origin` FROM `photo_tags`
+		WHERE `tag` = :from",
+		params!{"into" => into_id, "from" => from_id})?;
+	transaction.prep_exec(r"
+		DELETE FROM `photo_tags` WHERE `tag` = :from",
+		params!{"from" => from_id})?;
+	transaction.prep_exec(r"
+		UPDATE `tag_aliases` SET `tag` = :into WHERE `tag` = :from",
+		params!{"into" => into_id, "from" => from_id})?;
+	if keep_alias {
+		transaction.prep_exec(r"
+			INSERT IGNORE INTO `tag_aliases` (`alias`, `tag`)
+			SELECT `name`, :into FROM `tags` WHERE `id` = :from",
+			params!{"into" => into_id, "from" => from_id})?;
+	}
+	transaction.prep_exec(r"
+		DELETE FROM `tags` WHERE `id` = :from",
+		params!{"from" => from_id})?;
+
+	Ok(())
+}
+
+/// Removes all tag assignments of given origin from photos in source_id
+///
+/// Used before automatic tags are generated again. Returns number of
+/// removed assignments.
+pub fn clear_source_tags(connection: &my::Pool, source_id: u64, origin: &str)
+	-> Result<u64, my::Error> {
+	let result = connection.prep_exec(r"
+		DELETE photo_tags FROM `photo_tags`, `photos`
+		WHERE photos.id = photo_tags.photo AND photos.source = :source AND
+		photo_tags.origin = :origin",
+		params!{"source" => source_id, "origin" => origin})?;
+
+	Ok(result.affected_rows())
+}
+
+/// Returns names of tags assigned to the photo
+pub fn get_photo_tags(connection: &my::Pool, photo_id: u64)
+	-> Result<Vec<String>, my::Error> {
+	let mut tags: Vec<String> = vec![];
+
+	for row in connection.prep_exec(r"
+		SELECT tags.name FROM `tags`, `photo_tags`
+		WHERE tags.id = photo_tags.tag AND photo_tags.photo = :photo
+		ORDER BY tags.name",
+		params!{"photo" => photo_id})? {
+		tags.push(my::from_row(row?));
+	}
+
+	Ok(tags)
+}
+
+/// Reads :id url segment
+fn photo_id(request: &Request) -> u64 {
+	request.extensions.get::<Router>().unwrap()
+		.find("id").unwrap_or("0")
+		.parse::<u64>().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::renamed_tag;
+
+	#[test]
+	fn exact_match_is_replaced() {
+		assert_eq!(renamed_tag("cafe", "cafe", "coffee"), "coffee");
+	}
+
+	#[test]
+	fn nested_levels_are_kept() {
+		assert_eq!(renamed_tag("places/paris/louvre", "places/paris", "france/paris"),
+			"france/paris/louvre");
+	}
+
+	#[test]
+	fn accented_and_case_differing_names_are_renamed() {
+		// Collation matches "cafe" to these names, bytes differ
+		assert_eq!(renamed_tag("café", "cafe", "coffee"), "coffee");
+		assert_eq!(renamed_tag("CAFÉ/x", "cafe", "coffee"), "coffee/x");
+		assert_eq!(renamed_tag("Ü/ß/x", "u/ss", "letters"), "letters/x");
+	}
+}
@@ -0,0 +1,341 @@
+// Standard library includes
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Library includes
+use iron::prelude::*;
+use iron::status;
+use params::Map;
+use persistent::State;
+use mysql as my;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+use search::{param_list, param_string};
+use paths::PathPolicy;
+use Settings;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TrashedPhoto {
+	id: u64,
+	deleted_at: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TrashedSource {
+	id: u64,
+	full_path: String,
+	deleted_at: String
+}
+
+/// Moves photos and/or whole sources to trash
+///
+/// Accepts `photo_ids` as comma-separated list or array and `source_ids`
+/// the same way. Trashed photos disappear from all listings and searches
+/// until restored.
+pub fn trash(request: &mut Request) -> IronResult<Response> {
+	let params = request_params(request)?;
+	let (photo_ids, source_ids) = selection(&params);
+
+	if photo_ids.is_empty() && source_ids.is_empty() {
+		return Err(ApiError::bad_request("photo_ids or source_ids should be set").into());
+	}
+
+	let connection = db::get_connection(request);
+	set_deleted(&connection, &photo_ids, &source_ids, true)
+}
+
+/// Restores photos and/or sources from trash
+///
+/// Accepts `photo_ids` and `source_ids` like /api/trash. Photos trashed one
+/// by one stay in trash when their source is restored.
+pub fn restore(request: &mut Request) -> IronResult<Response> {
+	let params = request_params(request)?;
+	let (photo_ids, source_ids) = selection(&params);
+
+	if photo_ids.is_empty() && source_ids.is_empty() {
+		return Err(ApiError::bad_request("photo_ids or source_ids should be set").into());
+	}
+
+	let connection = db::get_connection(request);
+	set_deleted(&connection, &photo_ids, &source_ids, false)
+}
+
+/// Lists trashed photos and sources
+///
+/// Photos of trashed sources are not listed one by one.
+pub fn list_trash(request: &mut Request) -> IronResult<Response> {
+	let connection = db::get_connection(request);
+
+	let mut photos: Vec<TrashedPhoto> = vec![];
+	let result = connection.prep_exec(r"
+		SELECT `id`, DATE_FORMAT(`deleted_at`, '%Y-%m-%d %H:%i:%s')
+		FROM `photos` WHERE `deleted_at` IS NOT NULL
+		ORDER BY `deleted_at` DESC, `id`", ());
+	match result {
+		Ok(result) => {
+			result.for_each(|row| {
+				match row {
+					Ok(row) => {
+						let (id, deleted_at) = my::from_row(row);
+						photos.push(TrashedPhoto { id: id, deleted_at: deleted_at });
+					},
+					Err(_) => {}
+				}
+			});
+		},
+		Err(err) => return Err(ApiError::from(err).into())
+	}
+
+	let mut sources: Vec<TrashedSource> = vec![];
+	let result = connection.prep_exec(r"
+		SELECT `id`, `full_path`, DATE_FORMAT(`deleted_at`, '%Y-%m-%d %H:%i:%s')
+		FROM `sources` WHERE `deleted_at` IS NOT NULL
+		ORDER BY `deleted_at` DESC, `id`", ());
+	match result {
+		Ok(result) => {
+			result.for_each(|row| {
+				match row {
+					Ok(row) => {
+						let (id, full_path, deleted_at) = my::from_row(row);
+						sources.push(TrashedSource {
+							id: id,
+							full_path: full_path,
+							deleted_at: deleted_at
+						});
+					},
+					Err(_) => {}
+				}
+			});
+		},
+		Err(err) => return Err(ApiError::from(err).into())
+	}
+
+	let out_json = json!({
+		"photos": photos,
+		"sources": sources,
+	});
+	Ok(
+		Response::with(
+			(status::Ok, to_string_pretty(&out_json).unwrap())
+		)
+	)
+}
+
+/// Permanently removes trashed photos and sources
+///
+/// Accepts `photo_ids` and `source_ids` like /api/trash, or `all` = true to
+/// empty the whole trash. Only items which are in trash are purged. Photos
+/// are removed from DB together with their tags and album entries, and
+/// their renditions are removed from the gallery folder. Original files
+/// are removed only with `delete_originals` = true.
+pub fn purge(request: &mut Request) -> IronResult<Response> {
+	let policy = {
+		let rwlock = request.get::<State<Settings>>().unwrap();
+		let settings = rwlock.read().unwrap();
+		PathPolicy::from_settings(&settings)
+	};
+	let connection = db::get_connection(request);
+
+	let params = request_params(request)?;
+	let (photo_ids, source_ids) = selection(&params);
+	let all = param_flag(&params, "all");
+	let delete_originals = param_flag(&params, "delete_originals");
+
+	if photo_ids.is_empty() && source_ids.is_empty() && !all {
+		return Err(ApiError::bad_request("photo_ids, source_ids or all should be set").into());
+	}
+
+	match purge_trashed(&connection, &policy, &photo_ids, &source_ids, all, delete_originals) {
+		Ok(summary) => {
+			let out_json = json!({
+				"status": "ok",
+				"photos": summary.photos,
+				"sources": summary.sources,
+				"deleted_originals": summary.deleted_originals
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Result of purging the trash
+#[derive(Debug, Default)]
+pub struct PurgeSummary {
+	pub photos: u64,
+	pub sources: u64,
+	pub deleted_originals: u64
+}
+
+/// Permanently removes given trashed photos and sources
+///
+/// With `all` = true the whole trash is purged. Items which are not in
+/// trash are left as is. Renditions are removed from the gallery folder,
+/// originals only with `delete_originals` = true and only inside of source
+/// roots.
+pub fn purge_trashed(connection: &my::Pool, policy: &PathPolicy, photo_ids: &[String],
+	source_ids: &[String], all: bool, delete_originals: bool)
+	-> Result<PurgeSummary, my::Error> {
+
+	// Photos to purge along with their original paths
+	let mut photos: Vec<(u64, String)> = vec![];
+	for row in connection.prep_exec(r"
+		SELECT photos.id, CONCAT(sources.full_path, photos.relative_path)
+		FROM `photos`, `sources`
+		WHERE sources.id = photos.source AND (
+			(photos.deleted_at IS NOT NULL AND
+				(:all OR FIND_IN_SET(photos.id, :photo_ids))) OR
+			(sources.deleted_at IS NOT NULL AND
+				(:all_sources OR FIND_IN_SET(sources.id, :source_ids)))
+		)",
+		params!{
+			"all" => all,
+			"photo_ids" => photo_ids.join(","),
+			"all_sources" => all,
+			"source_ids" => source_ids.join(",")
+		})? {
+		photos.push(my::from_row(row?));
+	}
+
+	let ids = photos.iter()
+		.map(|&(id, _)| id.to_string())
+		.collect::<Vec<String>>()
+		.join(",");
+
+	let mut transaction = connection.start_transaction(false, None, None)?;
+
+	let queries = [
+		"DELETE FROM `photo_tags` WHERE FIND_IN_SET(`photo`, :ids)",
+		"DELETE FROM `album_photos` WHERE FIND_IN_SET(`photo`, :ids)",
+		"UPDATE `albums` SET `cover_photo` = NULL WHERE FIND_IN_SET(`cover_photo`, :ids)",
+		// Duplicates of a purged photo become visible again
+		"UPDATE `photos` SET `duplicate_of` = NULL WHERE FIND_IN_SET(`duplicate_of`, :ids)",
+		"DELETE FROM `photos` WHERE FIND_IN_SET(`id`, :ids)"
+	];
+	for query in queries.iter() {
+		transaction.prep_exec(*query, params!{"ids" => &ids})?;
+	}
+
+	let purged_sources = transaction.prep_exec(r"
+		DELETE FROM `sources` WHERE `deleted_at` IS NOT NULL AND
+		(:all OR FIND_IN_SET(`id`, :source_ids))",
+		params!{"all" => all, "source_ids" => source_ids.join(",")})?
+		.affected_rows();
+
+	transaction.commit()?;
+
+	// Files are removed only when DB rows are gone for sure
+	let sizes = rendition_folders(policy.gallery_folder());
+	let mut deleted_originals: u64 = 0;
+	for &(id, ref full_path) in photos.iter() {
+		remove_renditions(&sizes, id);
+		if delete_originals {
+			let result = policy.original(full_path)
+				.and_then(|path| fs::remove_file(path).map_err(ApiError::from));
+			match result {
+				Ok(_) => deleted_originals += 1,
+				Err(err) => println!("Unable to delete {}: {}", full_path, err)
+			}
+		}
+	}
+
+	Ok(PurgeSummary {
+		photos: photos.len() as u64,
+		sources: purged_sources,
+		deleted_originals: deleted_originals
+	})
+}
+
+/// Sets or clears deleted_at of photos and sources
+fn set_deleted(connection: &my::Pool, photo_ids: &[String], source_ids: &[String],
+	deleted: bool) -> IronResult<Response> {
+	let mut transaction = match connection.start_transaction(false, None, None) {
+		Ok(transaction) => transaction,
+		Err(err) => return Err(ApiError::from(err).into())
+	};
+
+	let value = if deleted { "IFNULL(`deleted_at`, NOW())" } else { "NULL" };
+	let mut updated: u64 = 0;
+	for &(table, ids) in [("photos", photo_ids), ("sources", source_ids)].iter() {
+		if ids.is_empty() {
+			continue;
+		}
+
+		let result = transaction.prep_exec(
+			format!("UPDATE `{}` SET `deleted_at` = {} WHERE FIND_IN_SET(`id`, :ids)",
+				table, value),
+			params!{"ids" => ids.join(",")});
+		match result {
+			Ok(result) => updated += result.affected_rows(),
+			Err(err) => return Err(ApiError::from(err).into())
+		}
+	}
+
+	match transaction.commit() {
+		Ok(_) => {
+			let out_json = json!({
+				"status": "ok",
+				"updated": updated
+			});
+			Ok(
+				Response::with(
+					(status::Ok, to_string_pretty(&out_json).unwrap())
+				)
+			)
+		},
+		Err(err) => Err(ApiError::from(err).into())
+	}
+}
+
+/// Lists folders of every rendition size in the gallery folder
+fn rendition_folders(gallery_folder: &Path) -> Vec<PathBuf> {
+	match fs::read_dir(gallery_folder) {
+		Ok(sizes) => sizes.filter_map(|entry| entry.ok())
+			.map(|entry| entry.path())
+			.filter(|path| path.is_dir())
+			.collect(),
+		Err(err) => {
+			println!("Unable to read {:?}: {:?}", gallery_folder, err);
+			vec![]
+		}
+	}
+}
+
+/// Removes renditions of the photo from every size folder
+fn remove_renditions(sizes: &[PathBuf], id: u64) {
+	for size in sizes.iter() {
+		let rendition = size.join(format!("{}.jpg", id));
+		if rendition.is_file() {
+			if let Err(err) = fs::remove_file(&rendition) {
+				println!("Unable to delete {:?}: {:?}", rendition, err);
+			}
+		}
+	}
+}
+
+/// Reads `photo_ids` and `source_ids` lists, malformed ids are skipped
+fn selection(params: &Map) -> (Vec<String>, Vec<String>) {
+	let ids = |name: &str| {
+		param_list(params, name)
+			.iter()
+			.filter_map(|id| u64::from_str(id).ok())
+			.map(|id| id.to_string())
+			.collect::<Vec<String>>()
+	};
+
+	(ids("photo_ids"), ids("source_ids"))
+}
+
+fn param_flag(params: &Map, name: &str) -> bool {
+	param_string(params, name)
+		.map(|value| value == "true" || value == "1")
+		.unwrap_or(false)
+}
@@ -0,0 +1,344 @@
+// Standard library includes
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::thread;
+
+// Library includes
+use iron::prelude::*;
+use iron::status;
+use persistent::State;
+use mysql as my;
+use serde_json;
+use serde_json::to_string_pretty;
+
+// Local includes
+use db;
+use error::{ApiError, request_params};
+use crawler;
+use paths::{PathPolicy, decode_path};
+use search::{COLOR_LABELS, param_string};
+use tags;
+use Settings;
+
+/// Number of files passed to a single exiftool call
+const EXIFTOOL_BATCH: usize = 100;
+
+/// Imports keywords of all photos in source_id as tags
+///
+/// Keywords are read with exiftool from the photo itself (IPTC Keywords,
+/// XMP dc:subject and lr:hierarchicalSubject) and from XMP sidecars next to
+/// it. Hierarchical keywords like "Places|Italy|Rome" become tag
+/// "Places/Italy/Rome" and flat keywords which are just parts of some
+/// hierarchical one are skipped.
+///
+/// Returns number of new tag assignments.
+pub fn import_keywords(connection: &my::Pool, policy: &PathPolicy, source_id: u64) -> u64 {
+	let documents = read_metadata(connection, policy, source_id,
+		&["-XMP-dc:Subject", "-XMP-lr:HierarchicalSubject", "-IPTC:Keywords"]);
+
+	let mut keywords: HashMap<u64, Keywords> = HashMap::new();
+	for &(id, ref document) in documents.iter() {
+		let entry = keywords.entry(id).or_insert(Keywords::default());
+		entry.flat.extend(json_strings(&document["Subject"]));
+		entry.flat.extend(json_strings(&document["Keywords"]));
+		entry.hierarchical.extend(json_strings(&document["HierarchicalSubject"]));
+	}
+
+	let mut assigned: u64 = 0;
+	for (id, keywords) in keywords.into_iter() {
+		match tags::assign_tags(connection, id, &keywords.to_tags(), tags::ORIGIN_IMPORT) {
+			Ok(count) => assigned += count,
+			Err(err) => println!("{:?}", err)
+		}
+	}
+
+	println!("Imported {} keywords in source_id: {}", assigned, source_id);
+	assigned
+}
+
+/// Imports star ratings and color labels of all photos in source_id
+///
+/// Rating is read from XMP or EXIF `Rating` field, sidecar taking
+/// precedence over the photo itself. Rejected photos (rating -1) are
+/// imported as unrated. Label is read from XMP `Label` field written by
+/// Lightroom and others. Marks already set in the gallery are kept.
+///
+/// Returns number of updated photos.
+pub fn import_ratings(connection: &my::Pool, policy: &PathPolicy, source_id: u64) -> u64 {
+	let documents = read_metadata(connection, policy, source_id,
+		&["-XMP-xmp:Rating", "-EXIF:Rating", "-XMP-xmp:Label"]);
+
+	// Sidecars come first, so the first value found wins
+	let mut marks: HashMap<u64, (Option<i64>, Option<String>)> = HashMap::new();
+	for &(id, ref document) in documents.iter() {
+		let entry = marks.entry(id).or_insert((None, None));
+		if entry.0.is_none() {
+			entry.0 = document["Rating"].as_i64()
+				.or_else(|| document["Rating"].as_str().and_then(|value| value.parse().ok()));
+		}
+		if entry.1.is_none() {
+			entry.1 = document["Label"].as_str()
+				.map(|label| label.trim().to_lowercase())
+				.filter(|label| COLOR_LABELS.contains(&label.as_str()));
+		}
+	}
+
+	let mut updated: u64 = 0;
+	for (id, (rating, color_label)) in marks.into_iter() {
+		let rating = rating.unwrap_or(0).max(0).min(5);
+		if rating == 0 && color_label.is_none() {
+			continue;
+		}
+
+		let result = connection.prep_exec(r"
+			UPDATE `photos`
+			SET `rating` = IF(`rating` = 0, :rating, `rating`),
+			`color_label` = IFNULL(`color_label`, :color_label)
+			WHERE `id` = :id",
+			params!{"rating" => rating, "color_label" => color_label, "id" => id});
+
+		match result {
+			Ok(result) => updated += result.affected_rows(),
+			Err(err) => println!("{:?}", err)
+		}
+	}
+
+	println!("Imported ratings of {} photos in source_id: {}", updated, source_id);
+	updated
+}
+
+/// Reads given exiftool tags of photos in source_id and their sidecars
+///
+/// Returns exiftool JSON document of every file along with the photo it
+/// describes. Sidecars go before the photo itself. Files outside of source
+/// roots are skipped.
+fn read_metadata(connection: &my::Pool, policy: &PathPolicy, source_id: u64, tags: &[&str])
+	-> Vec<(u64, serde_json::Value)> {
+	let images = match crawler::get_photos(connection, source_id) {
+		Ok(images) => images,
+		Err(err) => {
+			println!("{:?}", err);
+			return vec![];
+		}
+	};
+
+	// Every file to read mapped to the photo it describes
+	let mut files: Vec<(PathBuf, u64)> = vec![];
+	for (id, full_path) in images.into_iter() {
+		for sidecar in sidecar_paths(&full_path).into_iter() {
+			if decode_path(&sidecar).exists() {
+				if let Ok(sidecar) = policy.original(&sidecar) {
+					files.push((sidecar, id));
+				}
+			}
+		}
+		if let Ok(path) = policy.original(&full_path) {
+			files.push((path, id));
+		}
+	}
+
+	let mut result: Vec<(u64, serde_json::Value)> = vec![];
+
+	for batch in files.chunks(EXIFTOOL_BATCH) {
+		let output = Command::new("exiftool")
+			.arg("-j")
+			.arg("-q")
+			.args(tags)
+			.args(batch.iter().map(|&(ref path, _)| path))
+			.output();
+
+		let output = match output {
+			Ok(output) => output,
+			Err(err) => {
+				println!("Unable to run exiftool: {:?}", err);
+				return result;
+			}
+		};
+
+		let documents: Vec<serde_json::Value> =
+			serde_json::from_slice(&output.stdout).unwrap_or(vec![]);
+
+		// exiftool keeps order of files and reports names lossily
+		for &(ref path, id) in batch.iter() {
+			let source_file = path.to_string_lossy().into_owned();
+			let document = documents.iter()
+				.find(|document| document["SourceFile"].as_str() == Some(source_file.as_str()));
+			if let Some(document) = document {
+				result.push((id, document.clone()));
+			}
+		}
+	}
+
+	result
+}
+
+/// Writes tags of photos in the source to XMP sidecars
+///
+/// Accepts `source_id`. Runs in the background and returns immediately.
+/// Existing sidecar is updated in place, otherwise new "<name>.xmp" is
+/// created next to the photo.
+pub fn export_sidecars(request: &mut Request) -> IronResult<Response> {
+	let params = request_params(request)?;
+	let source_id = param_string(&params, "source_id")
+		.and_then(|value| u64::from_str(&value).ok())
+		.unwrap_or(0);
+
+	if source_id == 0 {
+		return Err(ApiError::bad_request("source_id should be set").into());
+	}
+
+	let policy = {
+		let rwlock = request.get::<State<Settings>>().unwrap();
+		let settings = rwlock.read().unwrap();
+		PathPolicy::from_settings(&settings)
+	};
+	let connection = db::get_connection(request);
+	thread::spawn(move || {
+		write_sidecars(&connection, &policy, source_id);
+	});
+
+	let out_json = json!({
+		"status": "accepted",
+	});
+	Ok(
+		Response::with(
+			(status::Accepted, to_string_pretty(&out_json).unwrap())
+		)
+	)
+}
+
+/// Writes tags of every photo in source_id into its XMP sidecar
+///
+/// Sidecars outside of source roots aren't written.
+fn write_sidecars(connection: &my::Pool, policy: &PathPolicy, source_id: u64) {
+	let images = match crawler::get_photos(connection, source_id) {
+		Ok(images) => images,
+		Err(err) => {
+			println!("{:?}", err);
+			return;
+		}
+	};
+	let mut written: u64 = 0;
+
+	for (id, full_path) in images.into_iter() {
+		let photo_tags = match tags::get_photo_tags(connection, id) {
+			Ok(photo_tags) => photo_tags,
+			Err(err) => {
+				println!("{:?}", err);
+				continue;
+			}
+		};
+
+		let candidates = sidecar_paths(&full_path);
+		let sidecar = candidates.iter()
+			.find(|path| decode_path(path).symlink_metadata().is_ok())
+			.unwrap_or(&candidates[0])
+			.clone();
+		let sidecar_path = match policy.sidecar(&sidecar) {
+			Ok(path) => path,
+			Err(err) => {
+				println!("Skipping {}: {}", sidecar, err);
+				continue;
+			}
+		};
+
+		let mut command = Command::new("exiftool");
+		command.arg("-q").arg("-overwrite_original");
+
+		// Assigning values replaces the whole list, empty value clears it
+		if photo_tags.is_empty() {
+			command.arg("-XMP-dc:Subject=").arg("-XMP-lr:HierarchicalSubject=");
+		}
+		for tag in photo_tags.iter() {
+			let leaf = tag.rsplit('/').next().unwrap_or(tag.as_str());
+			command.arg(format!("-XMP-dc:Subject={}", leaf));
+			if tag.contains('/') {
+				command.arg(format!("-XMP-lr:HierarchicalSubject={}",
+					tag.replace('/', "|")));
+			}
+		}
+
+		match command.arg(&sidecar_path).output() {
+			Ok(ref output) if output.status.success() => written += 1,
+			Ok(output) => {
+				println!("Unable to write {}: {}", sidecar,
+					String::from_utf8_lossy(&output.stderr));
+			},
+			Err(err) => println!("Unable to run exiftool: {:?}", err)
+		}
+	}
+
+	println!("Written {} sidecars in source_id: {}", written, source_id);
+}
+
+/// Keywords found for a single photo
+#[derive(Default, Debug)]
+struct Keywords {
+	flat: Vec<String>,
+	hierarchical: Vec<String>
+}
+
+impl Keywords {
+	/// Converts keywords into tag names without duplicates
+	fn to_tags(&self) -> Vec<String> {
+		let mut result: Vec<String> = vec![];
+		let mut components: Vec<String> = vec![];
+
+		for keyword in self.hierarchical.iter() {
+			let parts: Vec<String> = keyword.split('|')
+				.map(|part| part.trim().to_string())
+				.filter(|part| !part.is_empty())
+				.collect();
+			if parts.is_empty() {
+				continue;
+			}
+			components.extend(parts.iter().cloned());
+
+			let tag = parts.join("/");
+			if !result.contains(&tag) {
+				result.push(tag);
+			}
+		}
+
+		for keyword in self.flat.iter() {
+			let tag = keyword.trim().to_string();
+			if !tag.is_empty() && !components.contains(&tag) &&
+				!result.contains(&tag) {
+				result.push(tag);
+			}
+		}
+
+		result
+	}
+}
+
+/// Possible sidecar paths of the photo in order of preference
+///
+/// Lightroom names sidecar "IMG_0001.xmp", digiKam and darktable use
+/// "IMG_0001.jpg.xmp". Paths are in the stored form, see `encode_path`.
+fn sidecar_paths(full_path: &str) -> Vec<String> {
+	let path = Path::new(full_path);
+	let mut paths = vec![
+		path.with_extension("xmp").to_string_lossy().into_owned(),
+		path.with_extension("XMP").to_string_lossy().into_owned(),
+		format!("{}.xmp", full_path)
+	];
+	paths.dedup();
+	paths
+}
+
+/// Reads exiftool JSON value which is either a single value or a list
+///
+/// Numeric keywords like "2019" come as numbers.
+fn json_strings(value: &serde_json::Value) -> Vec<String> {
+	match *value {
+		serde_json::Value::Array(ref values) => {
+			values.iter().flat_map(|value| json_strings(value)).collect()
+		},
+		serde_json::Value::String(ref value) => vec![value.clone()],
+		serde_json::Value::Number(ref value) => vec![value.to_string()],
+		_ => vec![]
+	}
+}